@@ -2,48 +2,314 @@ use anyhow::{bail, Context, Result};
 use log::debug;
 use serde::{self, Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+use crate::{
+    error::DotError,
+    filter::Filter,
+    hash::HashAlgorithm,
+    ignore,
+    line_ending::LineEnding,
+    rename::{self, RenameRule},
+};
+
+/// Configuration file name standing for stdin
+pub const STDIN_CONFIG: &str = "-";
+
+/// Environment variables overriding the configuration file, with the setting they override
+/// and how they set it
+const ENV_VARS: [(&str, &str, fn(&mut Config, String)); 5] = [
+    ("DOT_REMOTE", "remote", |config, val| config.remote = val),
+    ("DOT_REMOTE_REGION", "remote_region", |config, val| {
+        config.remote_region = Some(val)
+    }),
+    ("DOT_REMOTE_PROFILE", "remote_profile", |config, val| {
+        config.remote_profile = Some(val)
+    }),
+    ("DOT_REMOTE_ENDPOINT", "remote_endpoint", |config, val| {
+        config.remote_endpoint = Some(val)
+    }),
+    ("DOT_ROOT_DIR", "root_dir", |config, val| {
+        config.root_dir = Some(val)
+    }),
+];
+
+/// A local directory matched with a prefix on the remote
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Root {
+    /// Local directory, resolved from the directory of the configuration file if relative. `~/`
+    /// is the home directory
+    pub path: PathBuf,
+    /// Prefix of the keys of the files in `path`, for example `config/`
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// How sync decides that a local file and a remote file are identical
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compare {
+    /// Retrieve the remote content and compare it with the local one
+    #[default]
+    Text,
+    /// Also compare the hash stored with the remote file, so that the identical files are not
+    /// retrieved. The MD5 ETag of the files is always compared, this is for the `hash`
+    /// algorithms storing their own hash. Falls back to `Text` when the remote hash is unknown
+    Hash,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Config {
+    /// Local directory containing the tracked files, the home directory by default. A relative
+    /// path is resolved from the directory of the configuration file and `~/` is the home
+    /// directory
     pub root_dir: Option<String>,
     pub remote: String,
     pub remote_profile: Option<String>,
     pub remote_region: Option<String>,
     pub remote_endpoint: Option<String>,
+    /// Patterns of the files to skip, with the `.dotignore` syntax, relative to the root
+    /// directory. They apply to both track and sync
+    #[serde(default)]
     pub ignore: Vec<String>,
+    /// File with more patterns to add to `ignore`, one per line. `~/` is the home directory
+    /// and a relative path is resolved from the directory of the configuration file
+    pub ignore_file: Option<String>,
+    /// Maximum number of snapshots kept on the remote, the oldest are removed first
+    pub keep_snapshots: Option<usize>,
+    /// Store the modification time of the files when uploading them and restore it when
+    /// downloading them. Enabled by default
+    pub preserve_mtime: Option<bool>,
+    /// Store the extended attributes of the files when uploading them and set them back when
+    /// downloading them. Disabled by default
+    pub preserve_xattrs: Option<bool>,
+    /// User-Agent sent with every request to the remote. Defaults to `dotfile/<version>`
+    pub user_agent: Option<String>,
+    /// Accept to pay for the requests to a requester pays bucket. Disabled by default
+    pub requester_pays: Option<bool>,
+    /// Files larger than this size, in bytes, are refused instead of being uploaded. No limit
+    /// by default
+    pub max_upload_size: Option<u64>,
+    /// Rules renaming the remote keys produced by `track`, written `old/prefix/=new/prefix/` or
+    /// `from_regex -> to` with `$1`, `$2`... for the capture groups. The first matching rule is
+    /// applied. Sync maps the renamed keys back to their local file, the ones of the regex rules
+    /// through an index track keeps on the remote
+    #[serde(default)]
+    pub rename_rules: Vec<String>,
+    /// Remote files larger than this size, in bytes, are skipped by sync unless
+    /// `--include-large` is given
+    pub large_file_threshold: Option<u64>,
+    /// Never change the remote: track, forget and snapshots are refused and sync only
+    /// downloads files
+    pub read_only: Option<bool>,
+    /// Files that only differ by the newlines at their end are considered identical by sync.
+    /// Disabled by default
+    pub ignore_trailing_newline: Option<bool>,
+    /// Line ending of the text files written by sync: `auto`, `lf`, `crlf` or `preserve`.
+    /// Except with `preserve`, the default, files that only differ by their line endings are
+    /// considered identical
+    pub line_ending: Option<LineEnding>,
+    /// Shell command run when sync leaves conflicts unresolved, with the conflicting keys in
+    /// the `DOT_CONFLICTS` environment variable, one per line
+    pub on_conflict: Option<String>,
+    /// How sync compares the local and remote files: `text` (the default) or `hash`
+    pub compare: Option<Compare>,
+    /// Hash algorithm of `compare = "hash"`: `blake3` (the default), `sha256` or `md5`. Except
+    /// for MD5, the hash is stored with the files uploaded from now on and checked with a HEAD
+    /// request per file. `md5` only compares the ETags, without any extra request
+    pub hash: Option<HashAlgorithm>,
+    /// Number of times a request failing with a connection error or a server error is retried,
+    /// 3 by default
+    pub max_retries: Option<u32>,
+    /// Delay before the first retry of a request, in milliseconds, doubled for each of the
+    /// next ones. 500 by default
+    pub retry_delay: Option<u64>,
+    /// Copy a local file to `<file>.dot-bak` before sync replaces it with the remote version.
+    /// Disabled by default
+    pub backup: Option<bool>,
+    /// Several root directories, each with its own prefix on the remote, as `[[root]]` tables.
+    /// They replace `root_dir` when set
+    #[serde(default, rename = "root", skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<Root>,
+    /// Commands the files are piped through when they are downloaded or uploaded, as
+    /// `[[filters]]` tables. The first filter whose pattern matches the key is used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<Filter>,
+    /// Where the value of each setting comes from, filled by `Config::load`
+    #[serde(skip)]
+    pub sources: BTreeMap<String, String>,
+    /// File next to the configuration file with the state of the remote keys at the last sync,
+    /// set by `Config::load` unless the configuration is read from stdin
+    #[serde(skip)]
+    pub synced_keys_file: Option<PathBuf>,
+    /// `rename_rules`, parsed by `Config::load`
+    #[serde(skip)]
+    pub renames: Vec<RenameRule>,
+    /// Keys renamed by a regex rule of `rename_rules`, with the key of their local file. Read
+    /// from the remote before the commands that need it, empty otherwise
+    #[serde(skip)]
+    pub renamed_keys: BTreeMap<String, String>,
+}
+
+/// Resolve `path` given in the configuration file `config_file_path`: `~/` is the home
+/// directory, and a relative path starts from the directory of the configuration file, so that
+/// the configuration and the dotfiles can be moved together. Relative paths are kept as they
+/// are when the configuration is read from stdin.
+pub fn resolve_path(config_file_path: &Path, path: &Path) -> Result<PathBuf> {
+    if let Ok(rest) = path.strip_prefix("~") {
+        return Ok(home::home_dir()
+            .context("Unable to find the home directory")?
+            .join(rest));
+    }
+    let path = path.to_path_buf();
+    if path.is_absolute() || config_file_path == Path::new(STDIN_CONFIG) {
+        return Ok(path);
+    }
+    Ok(config_file_path
+        .parent()
+        .map_or_else(|| path.clone(), |dir| dir.join(&path)))
 }
 
 impl Config {
+    /// Parse the configuration read from `reader`. `origin` tells where it comes from, in the
+    /// errors and in the source of each setting.
+    fn parse(mut reader: impl Read, origin: &str) -> Result<Config> {
+        let mut content = String::new();
+        reader
+            .read_to_string(&mut content)
+            .with_context(|| format!("Error reading the configuration from {}", origin))?;
+        if content.trim().is_empty() {
+            bail!(DotError::InvalidConfig(format!(
+                "The configuration read from {} is empty",
+                origin
+            )));
+        }
+        let mut config: Config = toml::from_str(&content).map_err(|err| {
+            DotError::InvalidConfig(format!("Invalid configuration in {}: {}", origin, err))
+        })?;
+        let table: toml::Table = toml::from_str(&content)?;
+        for field in table.keys() {
+            config
+                .sources
+                .insert(field.clone(), format!("from {}", origin));
+        }
+        Ok(config)
+    }
+
     pub fn load(config_file_path: &Path) -> Result<Config> {
-        let mut config: Config = if config_file_path.exists() {
+        // `-` reads the configuration from stdin, so that it never has to be written to disk
+        let mut config = if config_file_path == Path::new(STDIN_CONFIG) {
+            debug!("Loading config from stdin");
+            Config::parse(std::io::stdin(), "stdin")?
+        } else if config_file_path.exists() {
             debug!("Loading config from {:?}", config_file_path);
-            let config_data = File::open(config_file_path)
-                .and_then(|mut file| {
-                    let mut content = String::new();
-                    file.read_to_string(&mut content).map(|_| content)
-                })
-                .context("Error opening the configuration file")?;
-            toml::from_str(&config_data)?
+            let file =
+                File::open(config_file_path).context("Error opening the configuration file")?;
+            Config::parse(file, &config_file_path.display().to_string())?
         } else {
             Config::default()
         };
 
-        let _ = std::env::var("DOT_REMOTE").map(|val| config.remote = val);
-        let _ = std::env::var("DOT_REMOTE_REGION").map(|val| config.remote_region = Some(val));
-        let _ = std::env::var("DOT_REMOTE_PROFILE").map(|val| config.remote_profile = Some(val));
-        let _ = std::env::var("DOT_REMOTE_ENDPOINT").map(|val| config.remote_endpoint = Some(val));
-        let _ = std::env::var("DOT_ROOT_DIR").map(|val| config.root_dir = Some(val));
+        if let Some(ignore_file) = &config.ignore_file {
+            let path = resolve_path(config_file_path, Path::new(ignore_file))?;
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Error reading the ignore file {}", path.display()))?;
+            config.ignore.extend(ignore::parse_patterns(&content));
+        }
+
+        config.renames = config
+            .rename_rules
+            .iter()
+            .map(|rule| RenameRule::parse(rule))
+            .collect::<Result<_>>()
+            .map_err(|err| DotError::InvalidConfig(format!("{:#}", err)))?;
+
+        for (var, field, set) in ENV_VARS {
+            if let Ok(val) = std::env::var(var) {
+                set(&mut config, val);
+                config
+                    .sources
+                    .insert(field.to_string(), format!("from {}", var));
+            }
+        }
+
+        if config_file_path != Path::new(STDIN_CONFIG) {
+            let mut synced_keys_file = config_file_path.as_os_str().to_owned();
+            synced_keys_file.push(".synced");
+            config.synced_keys_file = Some(PathBuf::from(synced_keys_file));
+        }
 
         if config.remote == String::default() {
-            bail!("Could not find the configuration file. You can set its location with --config-file or create it with the configure' command. You can also set DOT_REMOTE without a configuration file")
+            bail!(DotError::InvalidConfig("Could not find the configuration file. You can set its location with --config-file or create it with the configure' command. You can also set DOT_REMOTE without a configuration file".to_string()))
         }
         Ok(config)
     }
 
+    /// Each setting with a value, with the place it comes from
+    pub fn explain(&self) -> Result<Vec<(String, String, String)>> {
+        let table: toml::Table = toml::from_str(&toml::to_string(self)?)?;
+        Ok(table
+            .into_iter()
+            .map(|(field, value)| {
+                let source = self
+                    .sources
+                    .get(&field)
+                    .cloned()
+                    .unwrap_or_else(|| "default".to_string());
+                (field, value.to_string(), source)
+            })
+            .collect())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.unwrap_or(false)
+    }
+
+    /// Fail if the remote is read-only, before attempting to `action`
+    pub fn ensure_writable(&self, action: &str) -> Result<()> {
+        if self.is_read_only() {
+            bail!("Cannot {}, the remote is configured as read-only", action);
+        }
+        Ok(())
+    }
+
+    /// Command the remote content of `key` goes through before being written locally
+    pub fn download_filter(&self, key: &str) -> Option<&str> {
+        self.filters
+            .iter()
+            .find(|filter| filter.matches(key))?
+            .download
+            .as_deref()
+    }
+
+    /// Command the local content of `key` goes through before being uploaded
+    pub fn upload_filter(&self, key: &str) -> Option<&str> {
+        self.filters
+            .iter()
+            .find(|filter| filter.matches(key))?
+            .upload
+            .as_deref()
+    }
+
+    /// Apply the first matching rule of `rename_rules` to `key`
+    pub fn rename_key(&self, key: &str) -> String {
+        rename::rename(&self.renames, key)
+    }
+
+    /// The key the remote `key` was renamed from by `rename_rules`, that is the one its local
+    /// file is found with
+    pub fn original_key(&self, key: &str) -> String {
+        rename::original(&self.renames, &self.renamed_keys, key)
+    }
+
+    pub fn has_regex_renames(&self) -> bool {
+        self.renames.iter().any(RenameRule::is_regex)
+    }
+
     pub fn save(&self, config_file_path: &Path) -> Result<()> {
         let mut file = File::create(config_file_path)?;
         let default_content = toml::to_string(&self)?;
@@ -51,3 +317,177 @@ impl Config {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a configuration file with `content`, written in a directory of its own
+    fn load(name: &str, content: &str) -> Result<Config> {
+        let dir =
+            std::env::temp_dir().join(format!("dotfile-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("config");
+        std::fs::write(&path, content)?;
+        let config = Config::load(&path);
+        std::fs::remove_dir_all(&dir)?;
+        config
+    }
+
+    #[test]
+    fn parse_records_the_source_of_each_setting() {
+        let content = "remote = \"bucket\"\nremote_region = \"eu-west-1\"\n";
+        let config = Config::parse(content.as_bytes(), "stdin").unwrap();
+        assert_eq!(config.remote, "bucket");
+        assert_eq!(config.remote_region.as_deref(), Some("eu-west-1"));
+        assert_eq!(config.sources["remote"], "from stdin");
+        assert!(!config.sources.contains_key("root_dir"));
+    }
+
+    #[test]
+    fn parse_refuses_an_empty_configuration() {
+        for content in ["", " \n\t\n"] {
+            let err = Config::parse(content.as_bytes(), "stdin").unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<DotError>(),
+                Some(DotError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_refuses_invalid_toml() {
+        let err = Config::parse("remote = ".as_bytes(), "stdin").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DotError>(),
+            Some(DotError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_path_from_the_config_file() {
+        let config_file = Path::new("/etc/dotfile/config");
+        assert_eq!(
+            resolve_path(config_file, Path::new("dotfiles")).unwrap(),
+            Path::new("/etc/dotfile/dotfiles")
+        );
+        assert_eq!(
+            resolve_path(config_file, Path::new("/srv/dotfiles")).unwrap(),
+            Path::new("/srv/dotfiles")
+        );
+        assert_eq!(
+            resolve_path(config_file, Path::new("~/dotfiles")).unwrap(),
+            home::home_dir().unwrap().join("dotfiles")
+        );
+        // Nothing to start from with stdin
+        assert_eq!(
+            resolve_path(Path::new(STDIN_CONFIG), Path::new("dotfiles")).unwrap(),
+            Path::new("dotfiles")
+        );
+    }
+
+    #[test]
+    fn relative_root_dir() {
+        let dir =
+            std::env::temp_dir().join(format!("dotfile-test-root-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        std::fs::write(&path, "remote = \"bucket\"\nroot_dir = \"dotfiles\"\n").unwrap();
+        let config = Config::load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let root_dir = config.unwrap().root_dir.unwrap();
+        assert_eq!(
+            resolve_path(&path, Path::new(&root_dir)).unwrap(),
+            dir.join("dotfiles")
+        );
+    }
+
+    #[test]
+    fn env_vars_override_the_file() {
+        std::env::set_var("DOT_REMOTE_ENDPOINT", "http://localhost:9000");
+        let config = load(
+            "env-override",
+            r#"
+                remote = "bucket"
+                remote_endpoint = "https://s3.example.com"
+            "#,
+        );
+        std::env::remove_var("DOT_REMOTE_ENDPOINT");
+        let explained = config.unwrap().explain().unwrap();
+        let setting = |field: &str| {
+            explained
+                .iter()
+                .find(|(name, ..)| name == field)
+                .cloned()
+                .unwrap()
+        };
+        let (_, value, source) = setting("remote_endpoint");
+        assert_eq!(value, "\"http://localhost:9000\"");
+        assert_eq!(source, "from DOT_REMOTE_ENDPOINT");
+        let (_, value, source) = setting("remote");
+        assert_eq!(value, "\"bucket\"");
+        assert!(source.starts_with("from ") && source.ends_with("config"));
+    }
+
+    #[test]
+    fn ignore_file_patterns_are_merged() {
+        let dir =
+            std::env::temp_dir().join(format!("dotfile-test-ignore-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ignore"), "# Editors\n*.swp\n\n.cache/\n").unwrap();
+        let path = dir.join("config");
+        std::fs::write(
+            &path,
+            "remote = \"bucket\"\nignore = [\"*.log\"]\nignore_file = \"ignore\"\n",
+        )
+        .unwrap();
+        let config = Config::load(&path);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config.unwrap().ignore, ["*.log", "*.swp", ".cache/"]);
+    }
+
+    #[test]
+    fn missing_ignore_file() {
+        let err = load(
+            "missing-ignore-file",
+            r#"
+                remote = "bucket"
+                ignore_file = "missing"
+            "#,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("Error reading the ignore file"));
+    }
+
+    #[test]
+    fn rename_key_applies_the_rules_in_order() {
+        let config = load(
+            "rename",
+            r#"
+                remote = "bucket"
+                rename_rules = [".vim/=vim/", '^\.config/([^/]+)/(.*)$ -> apps/$1/$2']
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.rename_key(".vim/vimrc"), "vim/vimrc");
+        assert_eq!(config.original_key("vim/vimrc"), ".vim/vimrc");
+        assert_eq!(config.rename_key(".config/git/config"), "apps/git/config");
+        assert_eq!(config.rename_key(".bashrc"), ".bashrc");
+    }
+
+    #[test]
+    fn invalid_rename_rules_are_refused() {
+        let err = load(
+            "invalid-rename",
+            r#"
+                remote = "bucket"
+                rename_rules = ["( -> x"]
+            "#,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DotError>(),
+            Some(DotError::InvalidConfig(_))
+        ));
+    }
+}
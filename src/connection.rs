@@ -1,11 +1,15 @@
 use std::{
+    collections::HashMap,
     error::Error,
     fmt::{self, Display, Formatter},
+    fs,
+    path::PathBuf,
     str::FromStr,
+    sync::{Mutex, OnceLock},
 };
 
-use log::info;
-use s3::{creds::Credentials, Region};
+use log::{debug, info};
+use s3::{creds::Credentials, Bucket, Region};
 
 use crate::config::Config;
 
@@ -15,6 +19,8 @@ pub struct ConnectionInfo {
     pub region: Region,
     pub credentials: Credentials,
     pub bucket_name: String,
+    pub user_agent: String,
+    pub requester_pays: bool,
 }
 
 impl ConnectionInfo {
@@ -22,15 +28,32 @@ impl ConnectionInfo {
         let credentials = get_credentials(config.remote_profile.clone())?;
         let region = get_region(
             credentials.clone(),
+            &config.remote,
             config.remote_region.clone(),
             config.remote_endpoint.clone(),
+            config.remote_profile.as_deref(),
         )?;
         Ok(ConnectionInfo {
             credentials,
             region,
             bucket_name: config.remote.clone(),
+            user_agent: config
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| format!("dotfile/{}", env!("CARGO_PKG_VERSION"))),
+            requester_pays: config.requester_pays.unwrap_or(false),
         })
     }
+
+    pub fn into_bucket(self) -> Result<Bucket> {
+        let mut bucket = Bucket::new(&self.bucket_name, self.region, self.credentials)
+            .context("Error when loading the remote bucket")?;
+        bucket.add_header("User-Agent", &self.user_agent);
+        if self.requester_pays {
+            bucket.add_header("x-amz-request-payer", "requester");
+        }
+        Ok(bucket)
+    }
 }
 
 pub fn get_credentials(remote_profile: Option<String>) -> Result<Credentials> {
@@ -61,14 +84,18 @@ impl Display for MissingRegionError {
 }
 
 pub fn get_region(
-    _credentials: Credentials,
+    credentials: Credentials,
+    bucket_name: &str,
     remote_region: Option<String>,
     remote_endpoint: Option<String>,
+    remote_profile: Option<&str>,
 ) -> Result<Region> {
-    // Fetch credentials in that order:
+    // Fetch the region in that order:
     // - from the environment variables
-    // - from AWS profile we we have set one in the config
-    // - Anonymous access (only for public buckets)
+    // - from the config, or the region of the AWS profile if we have set one in the config
+    // - from the location of the bucket
+    // - us-east-1 by default
+    let remote_region = remote_region.or_else(|| remote_profile.and_then(profile_region));
     Region::from_default_env().or_else(|_| {
         if let Some(remote_endpoint) = remote_endpoint {
             Ok(Region::Custom {
@@ -79,8 +106,93 @@ pub fn get_region(
             Region::from_str(&remote_region)
                 .with_context(|| format!("Invalid region name {}", remote_region))
         } else {
-            info!("Could not find an AWS region. Using the default 'us-east-1'");
-            Ok(Region::UsEast1)
+            Ok(bucket_region(bucket_name, credentials))
         }
     })
 }
+
+/// Region of the bucket, asked to S3 with GetBucketLocation. The answer is kept for the other
+/// connections made by the same command. us-east-1 if the bucket cannot tell, for example
+/// when the credentials are not allowed to ask.
+fn bucket_region(bucket_name: &str, credentials: Credentials) -> Region {
+    static REGIONS: OnceLock<Mutex<HashMap<String, Region>>> = OnceLock::new();
+    let mut regions = REGIONS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    regions
+        .entry(bucket_name.to_string())
+        .or_insert_with(|| {
+            let location = Bucket::new(bucket_name, Region::UsEast1, credentials)
+                .and_then(|bucket| bucket.location());
+            match location {
+                Ok((region, 200)) if !region.endpoint().is_empty() => {
+                    info!(
+                        "Using the region of the bucket, {}. Set remote_region in the config file to skip this lookup",
+                        region
+                    );
+                    region
+                }
+                _ => {
+                    info!("Could not find an AWS region. Using the default 'us-east-1'");
+                    Region::UsEast1
+                }
+            }
+        })
+        .clone()
+}
+
+/// Region set for `profile` in the AWS config file, `~/.aws/config` unless `AWS_CONFIG_FILE`
+/// is set, as the AWS CLI does
+fn profile_region(profile: &str) -> Option<String> {
+    let path = match std::env::var_os("AWS_CONFIG_FILE") {
+        Some(path) => PathBuf::from(path),
+        None => home::home_dir()?.join(".aws").join("config"),
+    };
+    let content = fs::read_to_string(path).ok()?;
+    // The default profile is `[default]`, the others are `[profile <name>]`
+    let section = if profile == "default" {
+        "default".to_string()
+    } else {
+        format!("profile {}", profile)
+    };
+    let mut in_section = false;
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            in_section = name.trim() == section;
+        } else if in_section {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.trim() == "region" {
+                    debug!("Using the region of the AWS profile {}", profile);
+                    return Some(value.trim().to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_of_the_aws_profile() {
+        let path = std::env::temp_dir().join(format!("dotfile-aws-config-{}", std::process::id()));
+        fs::write(
+            &path,
+            "[default]\nregion = eu-west-1\n\n[profile work]\noutput = json\nregion = us-west-2\n\n[profile empty]\n",
+        )
+        .unwrap();
+        std::env::set_var("AWS_CONFIG_FILE", &path);
+        assert_eq!(profile_region("default").as_deref(), Some("eu-west-1"));
+        assert_eq!(profile_region("work").as_deref(), Some("us-west-2"));
+        assert_eq!(profile_region("empty"), None);
+        assert_eq!(profile_region("missing"), None);
+        std::env::remove_var("AWS_CONFIG_FILE");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+/// Errors that a caller may want to tell apart, for example to pick the exit code. They are
+/// wrapped in `anyhow::Error` like the others and can be found back with `downcast_ref`.
+#[derive(Debug)]
+pub enum DotError {
+    /// The file, snapshot or bucket does not exist
+    NotFound(String),
+    /// The credentials were refused by the remote
+    Unauthorized(String),
+    /// The remote refused the change because of the state of the file
+    Conflict(String),
+    /// The remote could not be reached
+    Network(String),
+    /// The configuration is missing or invalid
+    InvalidConfig(String),
+    /// Any other status code returned by the remote
+    Remote(String),
+}
+
+impl DotError {
+    /// Error matching the status code returned by the remote, with `message` as description
+    pub fn from_status(status_code: u16, message: String) -> DotError {
+        match status_code {
+            401 | 403 => DotError::Unauthorized(message),
+            404 => DotError::NotFound(message),
+            409 | 412 | 423 => DotError::Conflict(message),
+            _ => DotError::Remote(message),
+        }
+    }
+
+    /// Exit code of the program when it stops with this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            DotError::Unauthorized(_) => 3,
+            DotError::NotFound(_) => 4,
+            DotError::Conflict(_) => 5,
+            DotError::Network(_) => 6,
+            DotError::InvalidConfig(_) => 7,
+            DotError::Remote(_) => 8,
+        }
+    }
+}
+
+impl Error for DotError {}
+
+impl Display for DotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DotError::NotFound(message)
+            | DotError::Unauthorized(message)
+            | DotError::Conflict(message)
+            | DotError::Network(message)
+            | DotError::InvalidConfig(message)
+            | DotError::Remote(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_codes() {
+        let error = |status_code| DotError::from_status(status_code, "message".to_string());
+        assert!(matches!(error(401), DotError::Unauthorized(_)));
+        assert!(matches!(error(403), DotError::Unauthorized(_)));
+        assert!(matches!(error(404), DotError::NotFound(_)));
+        assert!(matches!(error(409), DotError::Conflict(_)));
+        assert!(matches!(error(412), DotError::Conflict(_)));
+        assert!(matches!(error(500), DotError::Remote(_)));
+        assert_eq!(error(404).to_string(), "message");
+        assert_eq!(error(404).exit_code(), 4);
+    }
+}
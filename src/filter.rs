@@ -0,0 +1,93 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::ignore::glob_match;
+
+/// Commands the content of some files is piped through, as `[[filters]]` tables. They read the
+/// content on stdin and write the transformed content on stdout, for example to fill a template
+/// with `envsubst` or decrypt a file with `sops -d`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Filter {
+    /// Pattern of the keys the filter applies to, where `*` and `?` do not match `/`
+    pub key: String,
+    /// Command run on the remote content before it is written locally
+    pub download: Option<String>,
+    /// Command run on the local content before it is uploaded, the reverse of `download`
+    pub upload: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, key: &str) -> bool {
+        glob_match(&self.key, key)
+    }
+}
+
+/// Pipe `content` through the shell command `command` and return its output
+pub fn run(command: &str, key: &str, content: &[u8]) -> Result<Vec<u8>> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not run the filter {} on {}", command, key))?;
+    let mut stdin = child.stdin.take().context("The filter has no stdin")?;
+    // Written from another thread, a filter filling its stdout before reading all its input
+    // would block otherwise
+    let output = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(content));
+        let output = child.wait_with_output();
+        // A filter may exit without reading all its input, only its status matters then
+        let _ = writer.join();
+        output
+    })
+    .with_context(|| format!("Error running the filter {} on {}", command, key))?;
+    if !output.status.success() {
+        bail!(
+            "The filter {} failed on {}: {}",
+            command,
+            key,
+            output.status
+        );
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn identity_filter() {
+        // Larger than a pipe buffer, so that cat writes its output before reading everything
+        let content: Vec<u8> = (0..=255).cycle().take(1 << 20).collect();
+        assert_eq!(run("cat", "key", &content).unwrap(), content);
+        assert!(run("cat", "key", b"").unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn upload_and_download_round_trip() {
+        let content = b"set number\nsyntax on\n";
+        let uploaded = run("tr a-z A-Z", "key", content).unwrap();
+        assert_eq!(uploaded, b"SET NUMBER\nSYNTAX ON\n");
+        assert_eq!(run("tr A-Z a-z", "key", &uploaded).unwrap(), content);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn failing_filter() {
+        assert!(run("exit 3", "key", b"content").is_err());
+    }
+}
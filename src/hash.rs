@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Object metadata storing the hash of the file when it was uploaded, as `<algorithm>:<hex>`.
+/// Not needed for MD5, which S3 already stores in the ETag.
+pub const HASH_METADATA: &str = "x-amz-meta-hash";
+
+/// Algorithm used to compare the local and remote files, set with `hash` in the configuration
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// Matches the ETag of the objects uploaded in a single part, without any extra request.
+    /// Opt-in, as MD5 is broken and the multipart ETags are not an MD5 of the content
+    Md5,
+    Sha256,
+    #[default]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Hexadecimal digest of `bytes`
+    pub fn hash_bytes(self, bytes: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Md5 => format!("{:x}", md5::compute(bytes)),
+            HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(bytes)),
+            HashAlgorithm::Blake3 => blake3::hash(bytes).to_hex().to_string(),
+        }
+    }
+
+    /// Value of `HASH_METADATA` for `bytes`
+    pub fn metadata(self, bytes: &[u8]) -> String {
+        format!("{}:{}", self.name(), self.hash_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_digests() {
+        assert_eq!(
+            HashAlgorithm::Md5.hash_bytes(b"hello"),
+            "5d41402abc4b2a76b9719d911017c592"
+        );
+        assert_eq!(
+            HashAlgorithm::Sha256.hash_bytes(b"hello"),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            HashAlgorithm::Blake3.hash_bytes(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            HashAlgorithm::Md5.metadata(b"hello"),
+            "md5:5d41402abc4b2a76b9719d911017c592"
+        );
+    }
+
+    #[test]
+    fn blake3_by_default() {
+        assert_eq!(HashAlgorithm::default(), HashAlgorithm::Blake3);
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Name of the files listing the patterns to ignore in the directory containing them and in
+/// its sub-directories
+pub const IGNORE_FILE: &str = ".dotignore";
+
+/// The patterns of a `.dotignore` file, relative to the directory containing it
+pub struct IgnoreFile {
+    dir: PathBuf,
+    patterns: Vec<String>,
+    /// Where the patterns come from, reported when a file is ignored
+    source: PathBuf,
+}
+
+impl IgnoreFile {
+    /// Load the `.dotignore` file of `dir`, if there is one
+    pub fn load(dir: &Path) -> Result<Option<IgnoreFile>> {
+        let path = dir.join(IGNORE_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Error reading {}", path.display()))?;
+        Ok(Some(IgnoreFile {
+            dir: dir.to_path_buf(),
+            patterns: parse_patterns(&content),
+            source: path,
+        }))
+    }
+
+    /// The `ignore` patterns of the configuration, relative to the root directory `dir`
+    pub fn from_config(dir: &Path, patterns: &[String]) -> IgnoreFile {
+        IgnoreFile {
+            dir: dir.to_path_buf(),
+            patterns: patterns.to_vec(),
+            source: PathBuf::from("the configuration"),
+        }
+    }
+}
+
+/// The patterns of an ignore file, skipping the empty lines and the `#` comments
+pub fn parse_patterns(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Check `path` against the `.dotignore` files of its parent directories, outermost first.
+/// As with `.gitignore`, the last matching pattern wins and a pattern starting with `!`
+/// includes the file again. Returns the pattern ignoring the file and the `.dotignore` file
+/// it comes from, if the file is ignored.
+pub fn ignored_by<'a>(
+    ignore_files: &'a [IgnoreFile],
+    path: &Path,
+    is_dir: bool,
+) -> Option<(&'a str, PathBuf)> {
+    let mut ignored = None;
+    for ignore_file in ignore_files {
+        let Some(relative) = path
+            .strip_prefix(&ignore_file.dir)
+            .ok()
+            .and_then(Path::to_str)
+            .filter(|relative| !relative.is_empty())
+        else {
+            continue;
+        };
+        for full_pattern in &ignore_file.patterns {
+            let (negated, pattern) = match full_pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, full_pattern.as_str()),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            if dir_only && !is_dir {
+                continue;
+            }
+            // A pattern without a slash matches the name at any depth, otherwise it is
+            // relative to the directory of the `.dotignore` file
+            let matched = if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/'), relative)
+            } else {
+                relative
+                    .rsplit('/')
+                    .next()
+                    .is_some_and(|name| glob_match(pattern, name))
+            };
+            if matched {
+                ignored = (!negated).then(|| (full_pattern.as_str(), ignore_file.source.clone()));
+            }
+        }
+    }
+    ignored
+}
+
+/// Like `ignored_by`, for a file that may not exist locally, such as a remote key. Its parent
+/// directories are checked first, as they would be when walking the local tree.
+pub fn ignored_path<'a>(ignore_files: &'a [IgnoreFile], path: &Path) -> Option<(&'a str, PathBuf)> {
+    let mut ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+    ancestors.reverse();
+    ancestors
+        .into_iter()
+        .find_map(|dir| ignored_by(ignore_files, dir, true))
+        .or_else(|| ignored_by(ignore_files, path, false))
+}
+
+/// Match `text` against a pattern where `*` matches any number of characters and `?` a
+/// single one, neither of them matching `/`
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and of the text it was matched against
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some('?') if text[t] != '/' => {
+                p += 1;
+                t += 1;
+            }
+            Some(&c) if c != '?' && c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) if text[matched] != '/' => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    t = matched + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_wildcards_do_not_match_slashes() {
+        assert!(glob_match("*.swp", ".vimrc.swp"));
+        assert!(glob_match("?vimrc", ".vimrc"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("cache/*/tmp", "cache/app/tmp"));
+        assert!(!glob_match("*.swp", "dir/file.swp"));
+        assert!(!glob_match("a?b", "a/b"));
+        assert!(!glob_match("*.swp", ".vimrc"));
+    }
+
+    #[test]
+    fn last_matching_pattern_wins() {
+        let patterns = ["*.log", "!keep.log", "build/", "/local/*.tmp"].map(str::to_string);
+        let ignore_files = [IgnoreFile::from_config(Path::new("/root"), &patterns)];
+        let ignored = |path: &str, is_dir| ignored_by(&ignore_files, Path::new(path), is_dir);
+        assert_eq!(ignored("/root/a/debug.log", false).unwrap().0, "*.log");
+        assert!(ignored("/root/a/keep.log", false).is_none());
+        assert!(ignored("/root/a/build", true).is_some());
+        assert!(ignored("/root/a/build", false).is_none());
+        assert!(ignored("/root/local/x.tmp", false).is_some());
+        assert!(ignored("/root/a/local/x.tmp", false).is_none());
+        assert!(ignored("/elsewhere/debug.log", false).is_none());
+    }
+
+    #[test]
+    fn ignored_path_checks_the_parent_directories() {
+        let patterns = ["node_modules/", "*.log"].map(str::to_string);
+        let ignore_files = [IgnoreFile::from_config(Path::new("/root"), &patterns)];
+        let ignored = |path: &str| ignored_path(&ignore_files, Path::new(path));
+        assert_eq!(
+            ignored("/root/app/node_modules/pkg/index.js").unwrap().0,
+            "node_modules/"
+        );
+        assert_eq!(ignored("/root/app/debug.log").unwrap().0, "*.log");
+        assert!(ignored("/root/app/index.js").is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+/// Line ending of the text files written by sync, set with `line_ending` in the configuration
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    /// The native line ending of the platform, CRLF on Windows and LF elsewhere
+    Auto,
+    Lf,
+    Crlf,
+    /// Files are written as they are on the remote
+    #[default]
+    Preserve,
+}
+
+impl LineEnding {
+    /// `content` with this line ending. Binary content is returned as it is.
+    pub fn convert(self, content: &[u8]) -> Cow<'_, [u8]> {
+        if !is_text(content) {
+            return Cow::Borrowed(content);
+        }
+        match self {
+            LineEnding::Auto if cfg!(windows) => LineEnding::Crlf.convert(content),
+            LineEnding::Auto | LineEnding::Lf => to_lf(content),
+            LineEnding::Crlf => {
+                let lf = to_lf(content);
+                if !lf.contains(&b'\n') {
+                    return lf;
+                }
+                let mut crlf = Vec::with_capacity(lf.len() + lf.len() / 16);
+                for &byte in lf.iter() {
+                    if byte == b'\n' {
+                        crlf.push(b'\r');
+                    }
+                    crlf.push(byte);
+                }
+                Cow::Owned(crlf)
+            }
+            LineEnding::Preserve => Cow::Borrowed(content),
+        }
+    }
+
+    /// Check if `a` and `b` are the same once their line endings are normalized. Always false
+    /// with `Preserve`, where the line endings are part of the content.
+    pub fn same_text(self, a: &[u8], b: &[u8]) -> bool {
+        self != LineEnding::Preserve && is_text(a) && is_text(b) && to_lf(a) == to_lf(b)
+    }
+}
+
+/// `content` as text if it is valid UTF-8 without any NUL byte, `None` for binary content
+pub fn as_text(content: &[u8]) -> Option<&str> {
+    if content.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(content).ok()
+}
+
+fn is_text(content: &[u8]) -> bool {
+    as_text(content).is_some()
+}
+
+fn to_lf(content: &[u8]) -> Cow<'_, [u8]> {
+    if !content.windows(2).any(|pair| pair == b"\r\n") {
+        return Cow::Borrowed(content);
+    }
+    let mut lf = Vec::with_capacity(content.len());
+    for (index, &byte) in content.iter().enumerate() {
+        if byte != b'\r' || content.get(index + 1) != Some(&b'\n') {
+            lf.push(byte);
+        }
+    }
+    Cow::Owned(lf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_the_line_endings_of_text() {
+        assert_eq!(LineEnding::Lf.convert(b"a\r\nb\r\n").as_ref(), b"a\nb\n");
+        assert_eq!(
+            LineEnding::Crlf.convert(b"a\nb\r\n").as_ref(),
+            b"a\r\nb\r\n"
+        );
+        assert_eq!(
+            LineEnding::Preserve.convert(b"a\r\nb\n").as_ref(),
+            b"a\r\nb\n"
+        );
+        // A lone \r is not a line ending
+        assert_eq!(LineEnding::Lf.convert(b"a\rb").as_ref(), b"a\rb");
+    }
+
+    #[test]
+    fn binary_content_is_not_converted() {
+        let binary = b"\0a\r\nb";
+        assert_eq!(LineEnding::Lf.convert(binary).as_ref(), binary);
+        assert!(!LineEnding::Lf.same_text(binary, b"\0a\nb"));
+    }
+
+    #[test]
+    fn same_text_ignores_the_line_endings() {
+        assert!(LineEnding::Auto.same_text(b"a\r\nb", b"a\nb"));
+        assert!(LineEnding::Lf.same_text(b"a\nb", b"a\nb"));
+        assert!(!LineEnding::Lf.same_text(b"a\r\nb", b"a\nc"));
+        assert!(!LineEnding::Preserve.same_text(b"a\r\nb", b"a\nb"));
+    }
+}
@@ -1,28 +1,61 @@
 use clap::{Parser, Subcommand};
 
-use config::Config;
+use config::{Compare, Config, Root};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal,
+};
 use filetime::{self, set_file_times, FileTime};
 use home::home_dir;
-use log::{debug, info};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
 use path_absolutize::Absolutize;
-use s3::{self, Bucket};
+use s3::{self, request::ResponseData, serde_types::Object, Bucket};
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
     fs::{self, DirEntry},
-    io::Write,
-    path::{Path, PathBuf},
+    io::{IsTerminal, Write},
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
     str::FromStr,
-    time::SystemTime,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
 };
 
-use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use time::{
+    format_description::{self, well_known::Rfc3339},
+    Date, OffsetDateTime,
+};
 
 use diffy::{self, PatchFormatter};
 
-use crate::connection::ConnectionInfo;
+use crate::{
+    connection::ConnectionInfo,
+    error::DotError,
+    hash::{HashAlgorithm, HASH_METADATA},
+    ignore::{IgnoreFile, IGNORE_FILE},
+    line_ending::{as_text, LineEnding},
+    synced::SyncedFile,
+    xattrs::XATTRS_METADATA,
+};
 
 mod config;
 mod connection;
+mod error;
+mod filter;
+mod hash;
+mod ignore;
+mod line_ending;
+mod remote;
+mod rename;
+mod retry;
+mod serve;
+mod snapshot;
+mod synced;
+mod trash;
+mod xattrs;
 
 use anyhow::{bail, Context, Ok, Result};
 
@@ -38,9 +71,22 @@ struct Args {
     #[arg(short, long)]
     quiet: bool,
 
+    /// Only print errors and the summary at the end of a sync, for example in a cron job
+    #[arg(long)]
+    summary_only: bool,
+
+    /// Configuration file, or - to read it from stdin
     #[arg(long)]
     config_file: Option<PathBuf>,
 
+    /// Refuse any change to the remote, sync only downloads files
+    #[arg(long)]
+    read_only: bool,
+
+    /// Answer yes to every confirmation, for unattended runs
+    #[arg(short = 'y', long = "yes")]
+    assume_yes: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -48,13 +94,7 @@ struct Args {
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Add a new dotfile
-    Track {
-        /// Local filenames or directories
-        sources: Vec<PathBuf>,
-        /// Target file on the remote
-        #[arg(short, long)]
-        target: Option<String>,
-    },
+    Track(TrackArgs),
     /// Configure the Repository and create the configuration file. This can be skipped with environment variables
     Configure {
         /// Target bucket to store the dotfiles (DOT_REMOTE)
@@ -71,25 +111,250 @@ enum Commands {
         root_dir: Option<String>,
     },
     /// Forget a file in the remote
-    Forget { target: String },
+    Forget {
+        target: String,
+        /// Move the file to the trash instead of deleting it, see `trash restore`
+        #[arg(long)]
+        trash: bool,
+    },
+    /// Move a file to another key on the remote, without transferring its content
+    Move { from: String, to: String },
     /// Synchronize your local directory with the remote (download changes / upload changes)
-    Sync,
+    Sync(SyncArgs),
+    /// Download every remote file, overwriting the local versions
+    Pull {
+        /// Also delete the local files under the root directories that are not on the remote,
+        /// so that they mirror the remote exactly
+        #[arg(long)]
+        mirror: bool,
+        /// With --mirror, refuse to delete more than this number of local files
+        #[arg(long, default_value_t = 20, requires = "mirror")]
+        max_delete: usize,
+        /// Write the files under this directory instead of the root directories, keeping the
+        /// same layout
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+    },
     /// List all files tracked by dotfile
+    List {
+        /// Only list the files modified on the remote after this date (YYYY-MM-DD)
+        #[arg(long)]
+        modified_since: Option<String>,
+        /// Stop after listing this number of files
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Show the size of each file before its key
+        #[arg(long)]
+        sizes: bool,
+    },
+    /// Take a snapshot of the remote files (default), or manage the existing snapshots
+    Snapshot {
+        #[command(subcommand)]
+        action: Option<SnapshotAction>,
+    },
+    /// Manage the files moved to the trash by `forget --trash`
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+    /// Answer JSON commands read from stdin, one per line, for frontends that keep a single
+    /// process running
+    Serve,
+    /// Check the configuration and the connection to the remote
+    Doctor {
+        /// Offer to repair the problems found
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TrashAction {
+    /// List the files in the trash, with the time they were forgotten
+    List,
+    /// Move the last forgotten version of a file back to its original location
+    Restore { key: String },
+    /// Permanently delete the files in the trash
+    Empty,
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Show the value of each setting and where it comes from
+    Explain,
+}
+
+#[derive(clap::Args, Debug)]
+struct TrackArgs {
+    /// Local filenames or directories
+    sources: Vec<PathBuf>,
+    /// Target file on the remote
+    #[arg(short, long)]
+    target: Option<String>,
+    /// Give up on a single file after this many seconds and continue with the others
+    #[arg(long, value_name = "SECONDS")]
+    timeout_per_file: Option<u64>,
+    /// Upload the files even when the remote version is newer than the local one
+    #[arg(long)]
+    force: bool,
+    /// Also track the empty directories, so that sync creates them
+    #[arg(long)]
+    keep_empty_dirs: bool,
+    /// Only upload the files modified locally after this date (YYYY-MM-DD)
+    #[arg(long)]
+    changed_since: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SyncArgs {
+    /// Pick the conflicting files to resolve from a list before being prompted for each of them
+    #[arg(long)]
+    interactive_select: bool,
+    /// Only download the remote changes, conflicts are resolved with the remote version
+    #[arg(long, visible_alias = "download-only")]
+    only_download: bool,
+    /// Only upload the local changes, conflicts are resolved with the local version and the
+    /// files missing locally are skipped
+    #[arg(long, conflicts_with = "only_download")]
+    upload_only: bool,
+    /// Retrieve every remote file, even the ones whose size and modification time match the
+    /// local file. With --only-download, also overwrite the local files that are newer than the
+    /// remote
+    #[arg(long)]
+    force: bool,
+    /// Give up on a single file after this many seconds and continue with the others
+    #[arg(long, value_name = "SECONDS")]
+    timeout_per_file: Option<u64>,
+    /// Treat remote keys that only differ by their case as the same file, keeping the first
+    /// one listed. Without it, sync stops when it finds such keys
+    #[arg(long)]
+    case_insensitive: bool,
+    /// Check the size of the downloaded files, and remove the ones that were not fully written
+    #[arg(long)]
+    verify: bool,
+    /// Resolve every conflict the same way instead of asking. With --yes and no strategy, the
+    /// conflicting files are skipped
+    #[arg(long, visible_alias = "on-conflict", value_enum)]
+    strategy: Option<ConflictStrategy>,
+    /// Also sync the files larger than the large_file_threshold setting
+    #[arg(long)]
+    include_large: bool,
+    /// Fail without resolving anything if any file differs between local and remote
+    #[arg(long)]
+    abort_on_conflict: bool,
+    /// With --abort-on-conflict, still download the files missing locally before failing
+    #[arg(long, requires = "abort_on_conflict")]
+    download_missing: bool,
+    /// When a remote file is missing locally but a local file that is not on the remote has the
+    /// same content, move that file instead of downloading a copy, as the remote file was most
+    /// likely renamed
+    #[arg(long)]
+    detect_renames: bool,
+    /// Only report what would be done, without changing any local or remote file
+    #[arg(long)]
+    dry_run: bool,
+    /// Number of files retrieved at the same time. The conflicts are still resolved one by one
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+    /// Delete the local files whose key was on the remote at the previous sync and has been
+    /// removed since. Each deletion is confirmed unless --yes is given
+    #[arg(long)]
+    delete: bool,
+    /// Write the downloaded files under this directory instead of the root directories, keeping
+    /// the same layout. Nothing is uploaded and the sync state is left untouched, to preview a
+    /// restore without changing the live files
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// Show the modification time and size of both versions of a conflicting file, and which
+    /// one is newer, before the diff
+    #[arg(long)]
+    explain_conflict: bool,
+    /// Which version of a conflicting file the diff starts from
+    #[arg(long, value_enum, default_value_t = DiffDirection::LocalToRemote)]
+    diff_direction: DiffDirection,
+    /// Only sync the keys starting with this prefix, the path of the files relative to the root
+    /// directory. Can be given several times
+    #[arg(long, value_name = "PREFIX")]
+    only: Vec<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DiffDirection {
+    /// The diff shows the changes made by the remote version to the local one
+    LocalToRemote,
+    /// The diff shows the changes made by the local version to the remote one, as uploaded
+    RemoteToLocal,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ConflictStrategy {
+    /// Upload the local version
+    Upload,
+    /// Overwrite the local version with the remote one
+    Overwrite,
+    /// Keep the local version and save the remote one next to it as <file>.remote
+    KeepBoth,
+    /// Leave the file as it is
+    Skip,
+}
+
+impl ConflictStrategy {
+    /// The answer to the conflict prompt this strategy stands for
+    fn answer(self) -> &'static str {
+        match self {
+            ConflictStrategy::Upload => "u",
+            ConflictStrategy::Overwrite => "o",
+            ConflictStrategy::KeepBoth => "r",
+            ConflictStrategy::Skip => "s",
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotAction {
+    /// Copy all the remote files under a new snapshot
+    Create,
+    /// List the snapshots available on the remote
     List,
+    /// Copy the files of a snapshot back to their original location
+    Restore {
+        /// Name of the snapshot, as displayed by `snapshot list`
+        name: String,
+    },
+}
+
+fn main() -> ExitCode {
+    if let Err(err) = run() {
+        eprintln!("Error: {:?}", err);
+        return ExitCode::from(
+            err.downcast_ref::<DotError>()
+                .map_or(1, DotError::exit_code),
+        );
+    }
+    ExitCode::SUCCESS
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let args = Args::parse();
-    if args.quiet && args.verbose {
-        bail!("--quiet and --verbose cannot be used together");
+    if (args.quiet || args.summary_only) && args.verbose {
+        bail!("--quiet and --summary-only cannot be used with --verbose");
     }
-    if args.verbose {
-        simple_logger::init_with_level(log::Level::Debug)
-    } else if args.quiet {
-        simple_logger::init_with_level(log::Level::Error)
+    let level = if args.verbose {
+        log::LevelFilter::Debug
+    } else if args.quiet || args.summary_only {
+        log::LevelFilter::Error
     } else {
-        simple_logger::init_with_level(log::Level::Info)
-    }?;
+        log::LevelFilter::Info
+    };
+    log::set_max_level(level);
+    log::set_boxed_logger(Box::new(ProgressLogger(
+        simple_logger::SimpleLogger::new().with_level(level),
+    )))?;
 
     let config_file_path = args
         .config_file.as_ref()
@@ -109,6 +374,9 @@ fn main() -> Result<()> {
         root_dir,
     } = args.command
     {
+        if config_file_path == Path::new(config::STDIN_CONFIG) {
+            bail!("The configuration cannot be saved to stdin, use a file with --config-file");
+        }
         let mut config = Config::default();
         config.root_dir = root_dir.clone();
         config.remote = bucket.clone();
@@ -122,37 +390,278 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let config = config::Config::load(config_file_path)?;
+    if matches!(args.command, Commands::Serve)
+        && config_file_path == Path::new(config::STDIN_CONFIG)
+    {
+        bail!("serve reads its commands from stdin, the configuration cannot be read from it too");
+    }
+
+    let mut config = config::Config::load(config_file_path)?;
+    if args.read_only {
+        config.read_only = Some(true);
+        config
+            .sources
+            .insert("read_only".to_string(), "from --read-only".to_string());
+    }
+    retry::configure(&config);
 
-    let root_dir = config.root_dir.as_ref()
-        .map_or_else(
-            || home_dir()
-                .context("Unable to find the home directory to use as the root directory. You can set the root directory explicitly in the config file"),
-            |p| Ok(PathBuf::from_str(p)?),
-        )?;
-    let root_dir = &root_dir.as_path();
+    let roots = if config.roots.is_empty() {
+        let root_dir = config.root_dir.as_ref()
+            .map_or_else(
+                || home_dir()
+                    .context("Unable to find the home directory to use as the root directory. You can set the root directory explicitly in the config file"),
+                |p| Ok(PathBuf::from_str(p)?),
+            )?;
+        // A path given on the command line through DOT_ROOT_DIR stays relative to the
+        // current directory
+        let root_dir = if std::env::var_os("DOT_ROOT_DIR").is_some() {
+            root_dir
+        } else {
+            config::resolve_path(config_file_path, &root_dir)?
+        };
+        vec![Root {
+            path: root_dir,
+            prefix: String::new(),
+        }]
+    } else {
+        config
+            .roots
+            .iter()
+            .map(|root| {
+                Ok(Root {
+                    path: config::resolve_path(config_file_path, &root.path)?,
+                    prefix: root.prefix.clone(),
+                })
+            })
+            .collect::<Result<_>>()?
+    };
+
+    // The keys renamed by a regex rule can only be mapped back to their local file with the
+    // index kept on the remote
+    if config.has_regex_renames()
+        && matches!(
+            args.command,
+            Commands::Track(_) | Commands::Sync(_) | Commands::Pull { .. } | Commands::Serve
+        )
+    {
+        config.renamed_keys = rename::load_index(&ConnectionInfo::new(&config)?.into_bucket()?)?;
+    }
+
+    let output_dir = match &args.command {
+        Commands::Sync(sync_args) => sync_args.output_dir.as_ref(),
+        Commands::Pull { output_dir, .. } => output_dir.as_ref(),
+        _ => None,
+    };
+    let roots = match output_dir {
+        Some(output_dir) => {
+            // The scratch directory only receives the remote files, it must never be uploaded
+            // or recorded as the synced state of the real root directories
+            config.read_only = Some(true);
+            config.synced_keys_file = None;
+            roots
+                .into_iter()
+                .map(|root| Root {
+                    path: output_dir.join(&root.prefix),
+                    prefix: root.prefix,
+                })
+                .collect()
+        }
+        None => roots,
+    };
 
     match &args.command {
-        Commands::Sync => sync(root_dir, &config),
-        Commands::Track { sources, target } => track(sources, root_dir, target.clone(), &config),
-        Commands::Forget { target } => forget(target, &config),
+        Commands::Sync(sync_args) => {
+            let summary = sync(&roots, &config, sync_args, args.assume_yes)?;
+            // Printed outside of the logs so that it is kept with --summary-only
+            if !args.quiet || args.summary_only {
+                println!("{}", summary);
+            }
+            Ok(())
+        }
+        Commands::Track(track_args) => track(&roots, &config, track_args),
+        Commands::Forget { target, trash } => forget(target, *trash, &config, args.assume_yes),
+        Commands::Move { from, to } => move_file(from, to, &config, args.assume_yes),
         Commands::Configure { .. } => Ok(()),
-        Commands::List {} => list(&config),
+        Commands::List {
+            modified_since,
+            limit,
+            sizes,
+        } => list(&config, modified_since.as_deref(), *limit, *sizes),
+        Commands::Snapshot { action } => snapshot(action, &config, args.assume_yes),
+        Commands::Trash { action } => trash(action, &config, args.assume_yes),
+        Commands::Pull {
+            mirror, max_delete, ..
+        } => pull(&roots, &config, *mirror, *max_delete, args.assume_yes),
+        Commands::Serve => serve::serve(
+            &ConnectionInfo::new(&config)?.into_bucket()?,
+            &roots,
+            &config,
+        ),
+        Commands::Doctor { fix } => {
+            doctor(config_file_path, &roots, &config, *fix, args.assume_yes)
+        }
+        Commands::Config {
+            action: ConfigAction::Explain,
+        } => {
+            for (field, value, source) in config.explain()? {
+                println!("{} = {}  ({})", field, value, source);
+            }
+            Ok(())
+        }
     }
 }
 
-fn forget(target: &str, config: &Config) -> Result<()> {
-    let connection_info = ConnectionInfo::new(config)?;
-    let bucket = Bucket::new(
-        &connection_info.bucket_name,
-        connection_info.region,
-        connection_info.credentials,
-    )
-    .context("Error when loading the remote bucket")?;
+/// Check the configuration and the connection to the remote. With `fix`, offer to repair the
+/// problems that can be repaired locally.
+fn doctor(
+    config_file_path: &Path,
+    roots: &[Root],
+    config: &Config,
+    fix: bool,
+    assume_yes: bool,
+) -> Result<()> {
+    let mut problems = 0;
+    if !check_config_permissions(config_file_path, fix, assume_yes)? {
+        problems += 1;
+    }
+    for root in roots {
+        if root.path.is_dir() {
+            continue;
+        }
+        warn!("The root directory {} does not exist", root.path.display());
+        if fix && confirm(&format!("Create {}?", root.path.display()), assume_yes)? {
+            fs::create_dir_all(&root.path).with_context(|| {
+                format!("Cannot create the root directory {}", root.path.display())
+            })?;
+            info!("Created {}", root.path.display());
+        } else {
+            problems += 1;
+        }
+    }
+    if let Err(err) = ConnectionInfo::new(config)
+        .and_then(ConnectionInfo::into_bucket)
+        .and_then(|bucket| health_check(&bucket))
+    {
+        warn!("{:#}", err);
+        problems += 1;
+    }
+    if problems > 0 {
+        bail!("{} problem(s) found", problems);
+    }
+    info!("No problem found");
+    Ok(())
+}
+
+/// Check that only the owner can read the config file, as it may name credentials. Returns
+/// false if the problem remains.
+#[cfg(unix)]
+fn check_config_permissions(config_file_path: &Path, fix: bool, assume_yes: bool) -> Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if !config_file_path.exists() {
+        return Ok(true);
+    }
+    let mode = fs::metadata(config_file_path)
+        .context("Could not get metadata for the config file")?
+        .permissions()
+        .mode();
+    if mode & 0o077 == 0 {
+        return Ok(true);
+    }
+    warn!(
+        "The config file {} can be read by other users (mode {:o})",
+        config_file_path.display(),
+        mode & 0o777
+    );
+    if fix && confirm("Restrict it to its owner (mode 600)?", assume_yes)? {
+        fs::set_permissions(config_file_path, fs::Permissions::from_mode(0o600))
+            .context("Could not change the permissions of the config file")?;
+        info!("Restricted {} to mode 600", config_file_path.display());
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+fn check_config_permissions(
+    _config_file_path: &Path,
+    _fix: bool,
+    _assume_yes: bool,
+) -> Result<bool> {
+    Ok(true)
+}
+
+fn snapshot(action: &Option<SnapshotAction>, config: &Config, assume_yes: bool) -> Result<()> {
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+
+    match action {
+        None | Some(SnapshotAction::Create) => {
+            config.ensure_writable("create a snapshot")?;
+            snapshot::create(&bucket, config.keep_snapshots)
+        }
+        Some(SnapshotAction::List) => {
+            for name in snapshot::list(&bucket)? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        Some(SnapshotAction::Restore { name }) => {
+            config.ensure_writable("restore a snapshot")?;
+            if !confirm(
+                &format!(
+                    "Restoring {} overwrites the remote files it contains. Continue?",
+                    name
+                ),
+                assume_yes,
+            )? {
+                return Ok(());
+            }
+            snapshot::restore(&bucket, name)
+        }
+    }
+}
+
+fn trash(action: &TrashAction, config: &Config, assume_yes: bool) -> Result<()> {
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+
+    match action {
+        TrashAction::List => {
+            for (time, key) in trash::list(&bucket)? {
+                println!("{} {}", time, key);
+            }
+            Ok(())
+        }
+        TrashAction::Restore { key } => {
+            config.ensure_writable("restore a file from the trash")?;
+            trash::restore(&bucket, key)
+        }
+        TrashAction::Empty => {
+            config.ensure_writable("empty the trash")?;
+            if !confirm("Permanently delete the files in the trash?", assume_yes)? {
+                return Ok(());
+            }
+            trash::empty(&bucket)
+        }
+    }
+}
 
-    let (_, status_code) = bucket.head_object(target)?;
-    if status_code == 404 {
-        bail!("The file {} does not exist in the bucket", target)
+fn forget(target: &str, to_trash: bool, config: &Config, assume_yes: bool) -> Result<()> {
+    config.ensure_writable("forget a file")?;
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+
+    if !remote::exists(&bucket, target)? {
+        bail!(DotError::NotFound(format!(
+            "The file {} does not exist in the bucket",
+            target
+        )))
+    }
+    if to_trash {
+        trash::move_to_trash(&bucket, target)?;
+        info!("The file {} has been moved to the trash", target);
+        return Ok(());
+    }
+    if !confirm(&format!("Remove {} from the remote?", target), assume_yes)? {
+        return Ok(());
     }
 
     let response = bucket.delete_object(target)?;
@@ -164,221 +673,2045 @@ fn forget(target: &str, config: &Config) -> Result<()> {
             info!("The file {} has been removed", target);
             Ok(())
         },
-        403 => bail!("Deletion failed with error 403: Forbidden. Please check that your credentials allows you to delete files to the S3 bucket"),
-        err => bail!("Deletion failed with error code {}", err)
+        403 => bail!(DotError::Unauthorized("Deletion failed with error 403: Forbidden. Please check that your credentials allows you to delete files to the S3 bucket".to_string())),
+        err => bail!(DotError::from_status(err, format!("Deletion failed with error code {}", err)))
     }
 }
 
-fn list(config: &Config) -> Result<()> {
-    let connection_info = ConnectionInfo::new(config)?;
-    let bucket = Bucket::new(
-        &connection_info.bucket_name,
-        connection_info.region,
-        connection_info.credentials,
-    )
-    .context("Error when loading the remote bucket")?;
+fn move_file(from: &str, to: &str, config: &Config, assume_yes: bool) -> Result<()> {
+    config.ensure_writable("move a file")?;
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
 
-    let results = bucket
-        .list("".to_string(), None)
-        .context("Could not list the bucket content. It could be an invalid region or endpoint, invalid credentials, or network issues.")?;
+    if !remote::exists(&bucket, from)? {
+        bail!(DotError::NotFound(format!(
+            "The file {} does not exist in the bucket",
+            from
+        )))
+    }
+    if remote::exists(&bucket, to)?
+        && !confirm(
+            &format!("{} already exists on the remote, replace it?", to),
+            assume_yes,
+        )?
+    {
+        return Ok(());
+    }
+    remote::rename(&bucket, from, to)?;
+    info!("The file {} has been moved to {}", from, to);
+    Ok(())
+}
 
-    for result in results {
-        for file in result.contents {
+/// Midnight UTC of a date given on the command line as YYYY-MM-DD
+fn parse_date(date: &str) -> Result<OffsetDateTime> {
+    let format = format_description::parse("[year]-[month]-[day]")?;
+    Ok(Date::parse(date, &format)
+        .with_context(|| format!("Invalid date {}, expected YYYY-MM-DD", date))?
+        .midnight()
+        .assume_utc())
+}
+
+fn list(
+    config: &Config,
+    modified_since: Option<&str>,
+    limit: Option<usize>,
+    sizes: bool,
+) -> Result<()> {
+    let modified_since = modified_since.map(parse_date).transpose()?;
+
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+
+    if limit == Some(0) {
+        return Ok(());
+    }
+    let mut listed = 0;
+    list_each_while(&bucket, "", limit, &mut |file| {
+        if remote::is_internal_key(&file.key) {
+            return Ok(true);
+        }
+        if let Some(modified_since) = modified_since {
+            let last_modified = OffsetDateTime::parse(&file.last_modified, &Rfc3339)
+                .context("Error parsing the file modification date from the aws s3 header")?;
+            if last_modified < modified_since {
+                return Ok(true);
+            }
+        }
+        if sizes {
+            println!("{:>10}  {}", format_size(file.size), file.key);
+        } else {
             println!("{}", file.key);
         }
-    }
+        listed += 1;
+        Ok(limit.is_none_or(|limit| listed < limit))
+    })?;
     Ok(())
 }
 
-fn visit_dirs(dir: &Path, cb: &mut dyn FnMut(&DirEntry)) -> Result<()> {
+/// Check that the bucket can be reached with a minimal listing, so that a misconfiguration
+/// fails before any file is transferred.
+fn health_check(bucket: &Bucket) -> Result<()> {
+    let listing = bucket.list_page("".to_string(), None, None, None, Some(1));
+    if listing.is_err() && remote::serves_html(bucket) {
+        bail!(DotError::Remote(format!("Expected an S3 XML response from the remote bucket {} but got a text/html page, the endpoint may be misconfigured or require a login", bucket.name)));
+    }
+    let (_, status_code) = listing
+        .with_context(|| DotError::Network(format!("The remote bucket {} is unreachable. It could be an invalid region or endpoint, invalid credentials, or network issues.", bucket.name)))?;
+    match status_code {
+        200 => Ok(()),
+        403 => bail!(DotError::Unauthorized(format!("Access to the remote bucket {} failed with error 403: Forbidden. Please check your credentials", bucket.name))),
+        404 => bail!(DotError::NotFound(format!("The remote bucket {} does not exist", bucket.name))),
+        err => bail!(DotError::from_status(err, format!("The remote bucket {} is unreachable, error code {}", bucket.name, err))),
+    }
+}
+
+/// Prefixes to list to find the keys of all the `roots`, the whole remote if one of them has no
+/// prefix. The prefixes inside another one are left out, their keys are already listed.
+fn listing_prefixes(roots: &[Root]) -> Vec<&str> {
+    let mut prefixes: Vec<&str> = roots.iter().map(|root| root.prefix.as_str()).collect();
+    prefixes.sort_unstable();
+    let mut listed: Vec<&str> = vec![];
+    for prefix in prefixes {
+        if !listed.iter().any(|parent| prefix.starts_with(parent)) {
+            listed.push(prefix);
+        }
+    }
+    listed
+}
+
+/// Call `cb` for every object under `prefix`, one page of the listing at a time, so that the
+/// first files are processed before the whole bucket has been listed
+fn list_each(
+    bucket: &Bucket,
+    prefix: &str,
+    cb: &mut dyn FnMut(Object) -> Result<()>,
+) -> Result<()> {
+    list_each_while(bucket, prefix, None, &mut |object| {
+        cb(object).map(|()| true)
+    })
+}
+
+/// Like `list_each`, but stop as soon as `cb` returns false. `page_size` is the maximum number
+/// of objects retrieved with each request.
+fn list_each_while(
+    bucket: &Bucket,
+    prefix: &str,
+    page_size: Option<usize>,
+    cb: &mut dyn FnMut(Object) -> Result<bool>,
+) -> Result<()> {
+    let mut continuation_token = None;
+    loop {
+        let (page, _) = retry::with_retries(
+            "the bucket listing",
+            || {
+                bucket.list_page(
+                    prefix.to_string(),
+                    None,
+                    continuation_token.clone(),
+                    None,
+                    page_size,
+                )
+            },
+            |(_, status)| *status,
+        )
+        .context("Could not list the bucket content. It could be an invalid region or endpoint, invalid credentials, or network issues.")?;
+        for object in page.contents {
+            if !cb(object)? {
+                return Ok(());
+            }
+        }
+        match page.next_continuation_token {
+            Some(token) => continuation_token = Some(token),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Call `cb` for every file under `dir`, and for the directories without any file, skipping the
+/// files matched by the `.dotignore` files found along the way. `ignore_files` holds the
+/// `.dotignore` files of the parent directories. Returns the number of calls to `cb`. Unless
+/// `follow_symlinks` is set, the symbolic links to directories are passed to `cb` instead of
+/// being visited.
+fn visit_dirs(
+    dir: &Path,
+    follow_symlinks: bool,
+    ignore_files: &mut Vec<IgnoreFile>,
+    cb: &mut dyn FnMut(&DirEntry),
+) -> Result<usize> {
+    let mut visited = 0;
     if dir.is_dir() {
+        let ignore_file = IgnoreFile::load(dir)?;
+        let has_ignore_file = ignore_file.is_some();
+        ignore_files.extend(ignore_file);
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
-            if path.is_dir() {
-                visit_dirs(&path, cb)?;
+            let is_dir = if follow_symlinks {
+                path.is_dir()
+            } else {
+                entry.file_type()?.is_dir()
+            };
+            if let Some((pattern, ignore_file)) = ignore::ignored_by(ignore_files, &path, is_dir) {
+                debug!(
+                    "Ignoring {}, matched by {} in {}",
+                    path.display(),
+                    pattern,
+                    ignore_file.display()
+                );
+                continue;
+            }
+            if is_dir {
+                let visited_in_dir = visit_dirs(&path, follow_symlinks, ignore_files, cb)?;
+                if visited_in_dir == 0 {
+                    cb(&entry);
+                    visited += 1;
+                } else {
+                    visited += visited_in_dir;
+                }
             } else {
                 cb(&entry);
+                visited += 1;
             }
         }
+        if has_ignore_file {
+            ignore_files.pop();
+        }
     }
-    Ok(())
+    Ok(visited)
 }
 
-fn track(
-    sources: &Vec<PathBuf>,
-    root_dir: &Path,
-    remote_path: Option<String>,
-    config: &config::Config,
-) -> Result<()> {
-    let connection_info = ConnectionInfo::new(config)?;
-
-    let bucket = Bucket::new(
-        &connection_info.bucket_name,
-        connection_info.region,
-        connection_info.credentials,
-    )
-    .context("Error when loading the remote bucket")?;
+fn track(roots: &[Root], config: &config::Config, args: &TrackArgs) -> Result<()> {
+    config.ensure_writable("track files")?;
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+    // Credentials allowed to upload but not to list the bucket can still track files
+    if let Err(err) = health_check(&bucket) {
+        if !matches!(
+            err.downcast_ref::<DotError>(),
+            Some(DotError::Unauthorized(_))
+        ) {
+            return Err(err);
+        }
+        warn!("{:#}. Tracking the files anyway", err);
+    }
+    let timeout_per_file = args.timeout_per_file.map(Duration::from_secs);
+    let file_bucket = with_file_timeout(&bucket, timeout_per_file);
+    let mut timed_out = vec![];
+    let changed_since = args.changed_since.as_deref().map(parse_date).transpose()?;
+    let (mut uploaded, mut bytes_uploaded) = (0, 0);
+    let mut renamed_keys = config.renamed_keys.clone();
 
-    let mut files: HashSet<PathBuf> = HashSet::new();
+    // Sorted so that the upload order, and the logs, are the same from one run to the other
+    let mut files: BTreeMap<PathBuf, &Root> = BTreeMap::new();
 
-    let root_path = Path::new(&root_dir)
-        .absolutize()
-        .context("Could not find the absolute location of the root path")?;
+    let roots = roots
+        .iter()
+        .map(|root| {
+            Ok(Root {
+                path: root
+                    .path
+                    .absolutize()
+                    .context("Could not find the absolute location of the root path")?
+                    .to_path_buf(),
+                prefix: root.prefix.clone(),
+            })
+        })
+        .collect::<Result<Vec<Root>>>()?;
 
-    for source_path in sources {
+    for source_path in &args.sources {
         let source_path = source_path
             .absolutize()
             .context("Could not find the absolute location of the input file")?;
 
-        if let Some(remote_path) = remote_path {
+        if let Some(remote_path) = &args.target {
             if source_path.is_dir() {
                 bail!("The remote path can only be defined if there is a single source file")
             }
-            return upload_local_file(&source_path, &remote_path, &bucket);
+            upload_local_file(&source_path, remote_path, &file_bucket, config)?;
+            return Ok(());
         }
 
-        if !source_path.starts_with(&root_path) {
+        let Some((source_path, root)) = find_root(&roots, &source_path) else {
             bail!(
                 "Error, the file {} is not inside the root path {}",
                 source_path.display(),
-                root_path.display()
+                roots
+                    .iter()
+                    .map(|root| root.path.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
             );
-        }
+        };
 
         if source_path.is_dir() {
-            visit_dirs(&source_path, &mut |f| {
-                files.insert(f.path());
+            let mut ignore_files = vec![IgnoreFile::from_config(&root.path, &config.ignore)];
+            let visited = visit_dirs(&source_path, true, &mut ignore_files, &mut |f| {
+                let path = f.path();
+                if args.keep_empty_dirs || !path.is_dir() {
+                    files.insert(path, root);
+                }
             })?;
+            if visited == 0 && args.keep_empty_dirs {
+                files.insert(source_path, root);
+            }
         } else {
-            files.insert(source_path.to_path_buf());
+            files.insert(source_path, root);
         };
     }
 
-    for file in files {
+    let progress = progress_bar(files.len())?;
+    for (file, root) in files {
+        progress.inc(1);
+        progress.set_message(file.display().to_string());
+        if let Some(changed_since) = changed_since {
+            let modified = fs::metadata(&file)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| {
+                    format!("Could not read the modification time of {}", file.display())
+                })?;
+            if !file.is_dir() && OffsetDateTime::from(modified) < changed_since {
+                debug!(
+                    "Not modified since {}, skipping: {}",
+                    changed_since,
+                    file.display()
+                );
+                continue;
+            }
+        }
         let remote_path = file
-            .strip_prefix(&root_path)
+            .strip_prefix(&root.path)
             .context("Error when trying to generate the path in the S3 bucket")?;
 
-        upload_local_file(
-            &file,
+        // Empty directories are stored as an empty object whose key ends with a slash
+        let local_key = format!(
+            "{}{}{}",
+            root.prefix,
             remote_path.to_str().context("Invalid remote path")?,
-            &bucket,
-        )?
+            if file.is_dir() { "/" } else { "" }
+        );
+        let key = config.rename_key(&local_key);
+        if remote::is_internal_key(&key) {
+            warn!(
+                "The key {} is reserved for the snapshots and the trash, skipping it",
+                key
+            );
+            continue;
+        }
+        // Sync finds the local file of the keys renamed by a regex rule with the index
+        let needs_index = rename::original(&config.renames, &renamed_keys, &key) != local_key;
+        if needs_index
+            && (renamed_keys.contains_key(&key)
+                || !rename::renamed_by_regex(&config.renames, &local_key))
+        {
+            warn!(
+                "The rename rules change {} into {}, which sync would not map back to {}, skipping it",
+                local_key,
+                key,
+                file.display()
+            );
+            continue;
+        }
+        if !args.force && remote_is_newer(&bucket, &key, &file)? {
+            warn!(
+                "The remote version of {} is newer than the local file, skipping it (use --force to upload it anyway)",
+                key
+            );
+            continue;
+        }
+        if let Some(bytes) = transfer_file(&key, timeout_per_file, &mut timed_out, || {
+            upload_local_file(&file, &key, &file_bucket, config)
+        })? {
+            uploaded += 1;
+            bytes_uploaded += bytes;
+            progress.set_message(format!("{} ({})", key, format_size(bytes)));
+            if needs_index {
+                renamed_keys.insert(key, local_key);
+                rename::save_index(&bucket, &renamed_keys)?;
+            }
+        }
     }
+    progress.finish_and_clear();
+    report_timed_out(&timed_out);
+    info!(
+        "Uploaded {} file(s), {}",
+        uploaded,
+        format_size(bytes_uploaded)
+    );
     Ok(())
 }
 
-fn sync(root_dir: &Path, config: &config::Config) -> Result<()> {
-    let connection_info = ConnectionInfo::new(config)?;
-    let bucket = Bucket::new(
-        &connection_info.bucket_name,
-        connection_info.region,
-        connection_info.credentials,
-    )
-    .context("Error when loading the remote bucket")?;
-    info!("Listing files from {}", connection_info.bucket_name);
-
-    let results = bucket
-        .list("".to_string(), None)
-        .context("Could not list the bucket content. It could be an invalid region or endpoint, invalid credentials, or network issues.")?;
-
-    for result in results {
-        for file in result.contents {
-            debug!("Remote: {}, {}", file.key, file.last_modified);
-
-            let last_modified_s3 = OffsetDateTime::parse(&file.last_modified, &Rfc3339)
-                .context("Error parsing the file modification date from the aws s3 header")?;
+/// Download every remote file over its local version. With `mirror`, the local files that are
+/// not on the remote are deleted afterwards, as long as there are at most `max_delete` of them.
+fn pull(
+    roots: &[Root],
+    config: &Config,
+    mirror: bool,
+    max_delete: usize,
+    assume_yes: bool,
+) -> Result<()> {
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+    health_check(&bucket)?;
 
-            let local = root_dir.join(Path::new(&file.key));
-            let object = bucket
-                .get_object(&file.key)
-                .with_context(|| format!("Could not retrieve file {} from S3", &file.key))?;
+    let mut on_remote: HashSet<PathBuf> = HashSet::new();
+    let mut each_file = |file: Object| {
+        if remote::is_internal_key(&file.key) {
+            return Ok(());
+        }
+        if !is_safe_key(&file.key) {
+            warn!(
+                "    The key points outside of the root directory, refusing it: {}",
+                file.key
+            );
+            return Ok(());
+        }
+        let Some(local) = local_path(roots, &config.original_key(&file.key)) else {
+            info!("    No root directory for this key, skipping: {}", file.key);
+            return Ok(());
+        };
+        on_remote.insert(local.clone());
+        if file.key.ends_with('/') {
+            return fs::create_dir_all(&local)
+                .with_context(|| format!("Cannot create the directory {}", local.display()));
+        }
+        info!("    Retrieving {}", file.key);
+        if file.size > STREAM_THRESHOLD
+            && config.download_filter(&file.key).is_none()
+            && config.line_ending.unwrap_or_default() == LineEnding::Preserve
+        {
+            return stream_to_local_file(&bucket, &file, &local, config);
+        }
+        let object = retry::response(&file.key, || bucket.get_object(&file.key))
+            .with_context(|| format!("Could not retrieve file {} from S3", &file.key))?;
+        RemoteFile::new(file, local, object, config)?.download(false)
+    };
+    for prefix in listing_prefixes(roots) {
+        list_each(&bucket, prefix, &mut each_file)?;
+    }
 
-            if local.exists() {
-                debug!("    Found matching local file: {}", local.display());
-                let metadata = std::fs::metadata(&local)
-                    .context("Could not get metadata for the local file")?;
-                let last_modified_local = OffsetDateTime::from(
-                    metadata
-                        .modified()
-                        .context("Could not read modification time for the local file")?,
-                );
-                debug!(
-                    "    Conflict: Local file: {}, Remote file: {}",
-                    last_modified_local, last_modified_s3
-                );
-                let local_content = std::fs::read_to_string(&local)
-                    .context("Error reading the content of the local file")?;
-                let content_s3 = &String::from_utf8(object.bytes().to_vec())
-                    .context("The remote file is not a text file")?;
-                let patch = diffy::create_patch(&local_content, content_s3);
-                if patch.hunks().is_empty() {
-                    info!("    Identical content, skipping: {}", file.key);
-                } else {
-                    let patch_fmt = PatchFormatter::new().with_color();
-                    info!(
-                        "    {} - Original is local, Modified is remote:\n{}",
-                        file.key,
-                        patch_fmt.fmt_patch(&patch)
-                    );
-                    let response = ask_user("Upload (u) local version, Overwrite (o) local version with remote, Skip (s) this file, or Exit (e)", vec!["u", "o", "s", "e"]);
-                    match response.as_str() {
-                        "u" => upload_local_file(&local, &file.key, &bucket)?,
-                        "o" => replace_local_file(
-                            &local,
-                            object.bytes(),
-                            SystemTime::from(last_modified_s3),
-                        )?,
-                        "s" => continue,
-                        "e" => return Ok(()),
-                        _ => bail!("Unknown action"),
-                    }
-                }
-            } else {
-                info!("    Local version missing, retrieving {}", file.key);
-                replace_local_file(&local, object.bytes(), SystemTime::from(last_modified_s3))?;
+    if !mirror {
+        return Ok(());
+    }
+    let mut local_only = vec![];
+    for root in roots {
+        let mut ignore_files = vec![IgnoreFile::from_config(&root.path, &config.ignore)];
+        visit_dirs(&root.path, false, &mut ignore_files, &mut |entry| {
+            let path = entry.path();
+            // The .dotignore files only matter locally, they are kept even when not tracked
+            if !on_remote.contains(&path) && !path.is_dir() && entry.file_name() != IGNORE_FILE {
+                local_only.push(path);
             }
-        }
+        })?;
+    }
+    if local_only.is_empty() {
+        return Ok(());
+    }
+    for path in &local_only {
+        info!("    Not on the remote: {}", path.display());
     }
+    if local_only.len() > max_delete {
+        bail!(
+            "Mirroring the remote would delete {} local files, more than --max-delete {}",
+            local_only.len(),
+            max_delete
+        );
+    }
+    if !confirm(
+        &format!("Delete these {} local files?", local_only.len()),
+        assume_yes,
+    )? {
+        return Ok(());
+    }
+    for path in &local_only {
+        fs::remove_file(path).with_context(|| format!("Could not delete {}", path.display()))?;
+    }
+    info!("Deleted {} local files", local_only.len());
     Ok(())
 }
 
-fn upload_local_file(file_path: &Path, bucket_key: &str, bucket: &Bucket) -> Result<()> {
-    info!("Uploading {} to {}", file_path.display(), bucket_key);
-    let data = std::fs::read(file_path).context("Error reading file to upload")?;
-    let response = bucket.put_object(bucket_key, &data).with_context(|| {
-        format!(
-            "Error uploading file {} to the S3 bucket {}:{}",
-            file_path.display(),
-            bucket.name,
-            bucket_key
-        )
-    })?;
-    // I guess that's a bug from the s3 crate that isn't propagating errors from the http library.
+/// Find the root directory containing `path`, the innermost one if they are nested. Returns
+/// `path` expressed from that root directory, and the root directory.
+fn find_root<'a>(roots: &'a [Root], path: &Path) -> Option<(PathBuf, &'a Root)> {
+    roots
+        .iter()
+        .filter_map(|root| {
+            if path.starts_with(&root.path) {
+                return Some((path.to_path_buf(), root));
+            }
+            // The root path or the file may be behind a symlink, in which case they can only be
+            // compared once resolved. The file is then expressed from the unresolved root path,
+            // so that its key does not depend on where the symlinks point.
+            let resolved_root = root.path.canonicalize().ok()?;
+            let resolved_path = path.canonicalize().ok()?;
+            let relative = resolved_path.strip_prefix(&resolved_root).ok()?;
+            Some((root.path.join(relative), root))
+        })
+        .max_by_key(|(_, root)| root.path.components().count())
+}
+
+/// Check that `key` stays inside the root directory once joined to it: a hostile remote could
+/// otherwise use `..` or an absolute key to write anywhere on the disk
+fn is_safe_key(key: &str) -> bool {
+    Path::new(key)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Local path of the remote `key`, in the root directory with the longest matching prefix. The
+/// key must be renamed back with `Config::original_key` first.
+fn local_path(roots: &[Root], key: &str) -> Option<PathBuf> {
+    roots
+        .iter()
+        .filter_map(|root| key.strip_prefix(&root.prefix).map(|rest| (root, rest)))
+        .max_by_key(|(root, _)| root.prefix.len())
+        .map(|(root, rest)| root.path.join(rest))
+}
+
+/// A remote file and the local file it maps to, with the remote content already retrieved
+struct RemoteFile {
+    key: String,
+    local: PathBuf,
+    last_modified: OffsetDateTime,
+    /// Size of the object in the remote listing
+    size: u64,
+    /// Extended attributes to set on the local file, empty unless `preserve_xattrs` is enabled
+    xattrs: Vec<(OsString, Vec<u8>)>,
+    /// Line ending the local file is written with, if it is a text file
+    line_ending: LineEnding,
+    /// Keep a copy of the local file before replacing it
+    backup: bool,
+    /// The remote content, once through the download filter of the key if there is one
+    content: Vec<u8>,
+}
+
+impl RemoteFile {
+    fn new(file: Object, local: PathBuf, object: ResponseData, config: &Config) -> Result<Self> {
+        remote::check_not_html(&file.key, &object)?;
+        let last_modified_s3 = OffsetDateTime::parse(&file.last_modified, &Rfc3339)
+            .context("Error parsing the file modification date from the aws s3 header")?;
+        // The upload time is only a fallback for files uploaded without their original mtime
+        let last_modified = match original_mtime(&object) {
+            Some(mtime) if config.preserve_mtime.unwrap_or(true) => mtime,
+            _ => last_modified_s3,
+        };
+        // The size of a filtered file is the one of the filtered content written on disk
+        let (content, size) = match config.download_filter(&file.key) {
+            Some(command) => {
+                let content = filter::run(command, &file.key, object.as_slice())?;
+                let size = content.len() as u64;
+                (content, size)
+            }
+            None => (object.as_slice().to_vec(), file.size),
+        };
+        Ok(RemoteFile {
+            key: file.key,
+            local,
+            last_modified,
+            size,
+            xattrs: match object.headers().get(XATTRS_METADATA) {
+                Some(encoded) if config.preserve_xattrs.unwrap_or(false) => xattrs::decode(encoded),
+                _ => vec![],
+            },
+            line_ending: config.line_ending.unwrap_or_default(),
+            backup: config.backup.unwrap_or(false),
+            content,
+        })
+    }
+
+    /// Write the remote content to the local file. With `verify`, the size of the file on disk
+    /// is checked against the size in the listing, and the file is removed if they differ.
+    fn download(&self, verify: bool) -> Result<()> {
+        let content = self.line_ending.convert(&self.content);
+        replace_local_file(
+            &self.local,
+            &content,
+            SystemTime::from(self.last_modified),
+            self.backup,
+        )?;
+        if verify {
+            let size = fs::metadata(&self.local)
+                .context("Could not get metadata for the downloaded file")?
+                .len();
+            // Converting the line endings changes the size of the file
+            let expected = match content {
+                Cow::Borrowed(_) => self.size,
+                Cow::Owned(converted) => converted.len() as u64,
+            };
+            if size != expected {
+                fs::remove_file(&self.local).with_context(|| {
+                    format!("Could not remove the partial file {}", self.local.display())
+                })?;
+                bail!(
+                    "The downloaded file {} is {} bytes instead of {}, it has been removed",
+                    self.local.display(),
+                    size,
+                    expected
+                );
+            }
+        }
+        xattrs::apply(&self.local, &self.xattrs);
+        Ok(())
+    }
+}
+
+enum SyncAction {
+    /// The local file is missing and will be downloaded
+    Download,
+    /// Local and remote content are the same
+    Identical,
+    /// Only the remote file changed since the previous sync, it replaces the local one
+    RemoteChanged,
+    /// Only the local file changed since the previous sync, it replaces the remote one
+    LocalChanged,
+    /// Both sides changed since the previous sync without overlapping, the merged content
+    /// replaces the local and remote versions
+    Merged { content: Vec<u8> },
+    /// Local and remote content differ, the user must decide
+    Conflict {
+        local_content: Vec<u8>,
+        last_modified_local: OffsetDateTime,
+    },
+}
+
+/// What a sync did, printed at the end even with --summary-only
+#[derive(Default, serde::Serialize)]
+struct SyncSummary {
+    dry_run: bool,
+    downloaded: usize,
+    /// Local files moved to the new location of a file renamed on the remote
+    moved: usize,
+    /// Local files deleted because they were deleted on the remote
+    deleted: usize,
+    /// Remote files deleted because they were deleted locally
+    deleted_remote: usize,
+    /// Files changed on both sides and merged automatically, also counted as uploaded
+    merged: usize,
+    uploaded: usize,
+    identical: usize,
+    /// Conflicts resolved with one version, a merge or both versions
+    resolved: usize,
+    /// Conflicts left as they were
+    unresolved: usize,
+    /// Files given up on after a timeout
+    failed: usize,
+    /// Size of the content retrieved from the remote, including the files compared
+    bytes_downloaded: u64,
+    bytes_uploaded: u64,
+}
+
+impl std::fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.dry_run {
+            return write!(
+                f,
+                "Dry run: {} to download, {} to delete, {} identical, {} to resolve ({} received)",
+                self.downloaded,
+                self.deleted,
+                self.identical,
+                self.unresolved,
+                format_size(self.bytes_downloaded)
+            );
+        }
+        write!(f, "{} downloaded, ", self.downloaded)?;
+        if self.moved > 0 {
+            write!(f, "{} moved, ", self.moved)?;
+        }
+        if self.deleted > 0 {
+            write!(f, "{} deleted, ", self.deleted)?;
+        }
+        if self.deleted_remote > 0 {
+            write!(f, "{} deleted from the remote, ", self.deleted_remote)?;
+        }
+        if self.merged > 0 {
+            write!(f, "{} merged, ", self.merged)?;
+        }
+        if self.resolved > 0 {
+            write!(f, "{} conflict(s) resolved, ", self.resolved)?;
+        }
+        if self.failed > 0 {
+            write!(f, "{} failed, ", self.failed)?;
+        }
+        write!(
+            f,
+            "{} uploaded, {} identical, {} unresolved ({} received, {} sent)",
+            self.uploaded,
+            self.identical,
+            self.unresolved,
+            format_size(self.bytes_downloaded),
+            format_size(self.bytes_uploaded)
+        )
+    }
+}
+
+/// The progress bar drawn last, cleared while a log line is written so that the two do not
+/// interleave
+static PROGRESS: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Writes the logs of `SimpleLogger` around the progress bar
+struct ProgressLogger(simple_logger::SimpleLogger);
+
+impl log::Log for ProgressLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        log::Log::enabled(&self.0, metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let progress = PROGRESS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        match progress {
+            Some(progress) => progress.suspend(|| log::Log::log(&self.0, record)),
+            None => log::Log::log(&self.0, record),
+        }
+    }
+
+    fn flush(&self) {
+        log::Log::flush(&self.0)
+    }
+}
+
+/// Progress bar counting the files transferred out of `len`. Hidden with --quiet and when stderr
+/// is not a terminal, so that nothing is drawn in the logs of an unattended run.
+fn progress_bar(len: usize) -> Result<ProgressBar> {
+    if log::max_level() < log::LevelFilter::Info || !std::io::stderr().is_terminal() {
+        return Ok(ProgressBar::hidden());
+    }
+    let progress = ProgressBar::new(len as u64).with_style(ProgressStyle::with_template(
+        "{pos} of {len} files {wide_msg}",
+    )?);
+    *PROGRESS.lock().unwrap_or_else(PoisonError::into_inner) = Some(progress.clone());
+    Ok(progress)
+}
+
+/// `bytes` in a human readable unit, such as `4.2 MiB`
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn sync(
+    roots: &[Root],
+    config: &config::Config,
+    args: &SyncArgs,
+    assume_yes: bool,
+) -> Result<SyncSummary> {
+    if args.upload_only {
+        config.ensure_writable("upload the local changes")?;
+    }
+    let bucket = ConnectionInfo::new(config)?.into_bucket()?;
+    health_check(&bucket)?;
+    let timeout_per_file = args.timeout_per_file.map(Duration::from_secs);
+    let file_bucket = with_file_timeout(&bucket, timeout_per_file);
+    let mut timed_out = vec![];
+    let mut summary = SyncSummary {
+        dry_run: args.dry_run,
+        ..SyncSummary::default()
+    };
+    info!("Listing files from {}", bucket.name);
+
+    // The files changed on the remote only are written as soon as they are retrieved. The others
+    // are kept until everything is compared, so that the conflicts are known up front, but only
+    // the files still to write keep their content in memory
+    let mut actions = vec![];
+    // Lowercase version of the keys already listed, to find the keys that would be the same
+    // file on a case-insensitive file system
+    let mut folded_keys: HashMap<String, String> = HashMap::new();
+    // Local paths of all the remote files, to find the local files that are not on the remote
+    let mut on_remote = HashSet::new();
+    let ignore_files: Vec<IgnoreFile> = roots
+        .iter()
+        .map(|root| IgnoreFile::from_config(&root.path, &config.ignore))
+        .collect();
+    // Every key of the remote with its ETag, saved at the end to find the files deleted or
+    // unchanged at the next sync
+    let mut remote_keys: BTreeMap<String, Option<String>> = BTreeMap::new();
+    // Keys whose local and remote versions are identical once synced
+    let mut in_sync = HashSet::new();
+    // Keys left out by --only, whose state is kept as it was
+    let mut not_selected = HashSet::new();
+    // Remote files to retrieve to compare them with the local ones
+    let mut pending = vec![];
+    let previously_synced = match &config.synced_keys_file {
+        Some(path) => synced::load(path)?,
+        None => BTreeMap::new(),
+    };
+    let mut each_file = |file: Object| {
+        if remote::is_internal_key(&file.key) {
+            return Ok(());
+        }
+        debug!("Remote: {}, {}", file.key, file.last_modified);
+        remote_keys.insert(file.key.clone(), file.e_tag.clone());
+        if !args.only.is_empty()
+            && !args
+                .only
+                .iter()
+                .any(|prefix| file.key.starts_with(prefix.trim_start_matches("./")))
+        {
+            debug!("    Not selected by --only, skipping: {}", file.key);
+            not_selected.insert(file.key);
+            return Ok(());
+        }
+
+        if let Some(first) = folded_keys.get(&file.key.to_lowercase()) {
+            if !args.case_insensitive {
+                bail!(
+                    "The remote keys {} and {} only differ by their case and would overwrite each other on a case-insensitive file system. Use --case-insensitive to only keep {}",
+                    first,
+                    file.key,
+                    first
+                );
+            }
+            warn!(
+                "    {} only differs from {} by its case, skipping it",
+                file.key, first
+            );
+            return Ok(());
+        }
+        folded_keys.insert(file.key.to_lowercase(), file.key.clone());
+
+        if let Some(threshold) = config.large_file_threshold {
+            if file.size > threshold && !args.include_large {
+                info!("    Large ({} bytes), skipped: {}", file.size, file.key);
+                return Ok(());
+            }
+        }
+
+        if !is_safe_key(&file.key) {
+            warn!(
+                "    The key points outside of the root directory, refusing it: {}",
+                file.key
+            );
+            return Ok(());
+        }
+        let Some(local) = local_path(roots, &config.original_key(&file.key)) else {
+            info!("    No root directory for this key, skipping: {}", file.key);
+            return Ok(());
+        };
+        if let Some((pattern, source)) = ignore::ignored_path(&ignore_files, &local) {
+            debug!(
+                "    Ignoring {}, matched by {} in {}",
+                file.key,
+                pattern,
+                source.display()
+            );
+            return Ok(());
+        }
+        on_remote.insert(local.clone());
+        if file.key.ends_with('/') {
+            if !local.is_dir() && args.dry_run {
+                info!("    Would create the empty directory {}", file.key);
+            } else if !local.is_dir() {
+                info!("    Creating the empty directory {}", file.key);
+                fs::create_dir_all(&local)
+                    .with_context(|| format!("Cannot create the directory {}", local.display()))?;
+            }
+            return Ok(());
+        }
+        let unchanged = previously_synced
+            .get(&file.key)
+            .is_some_and(|synced| synced.unchanged(file.e_tag.as_deref(), &local));
+        if !args.force && (unchanged || unchanged_since_download(&bucket, &file, &local, config)?) {
+            info!("    Unchanged since the last sync, skipping: {}", file.key);
+            summary.identical += 1;
+            in_sync.insert(file.key);
+            return Ok(());
+        }
+        // The content is only retrieved when the local file may differ from the remote one
+        if same_hash(&bucket, &file, &local, config)? {
+            info!("    Identical content, skipping: {}", file.key);
+            summary.identical += 1;
+            in_sync.insert(file.key);
+            return Ok(());
+        }
+        pending.push((file, local));
+        Ok(())
+    };
+    for prefix in listing_prefixes(roots) {
+        list_each(&bucket, prefix, &mut each_file)?;
+    }
+
+    // Nothing is written before the end with --abort-on-conflict, which may write nothing
+    let write_early = !args.dry_run && !args.abort_on_conflict && !args.upload_only;
+    let strategy = args
+        .strategy
+        .or(assume_yes.then_some(ConflictStrategy::Skip));
+    // A file synced before and now missing locally was deleted on purpose, the user can
+    // delete it from the remote too. It is downloaded again when nobody can be asked.
+    let ask_deletions = strategy.is_none()
+        && !args.only_download
+        && !args.upload_only
+        && !config.is_read_only()
+        && std::io::stdin().is_terminal();
+    // Only the files that were written locally by a previous sync can have been deleted, the
+    // others were skipped or never retrieved
+    let deleted_locally = |key: &str| {
+        ask_deletions
+            && previously_synced
+                .get(key)
+                .is_some_and(|synced| synced.local.is_some())
+    };
+    let mut local_only = vec![];
+    if args.detect_renames && pending.iter().any(|(_, local)| !local.exists()) {
+        for root in roots {
+            let mut ignore_files = vec![IgnoreFile::from_config(&root.path, &config.ignore)];
+            visit_dirs(&root.path, false, &mut ignore_files, &mut |entry| {
+                let path = entry.path();
+                if !on_remote.contains(&path) && !path.is_dir() && entry.file_name() != IGNORE_FILE
+                {
+                    local_only.push(path);
+                }
+            })?;
+        }
+    }
+
+    // The contents are retrieved `--jobs` at a time, then compared in the listing order
+    let jobs = args.jobs.max(1);
+    let progress = progress_bar(pending.len())?;
+    let mut pending = pending.into_iter().peekable();
+    while pending.peek().is_some() {
+        let batch: Vec<(Object, PathBuf)> = pending.by_ref().take(jobs).collect();
+        let keys: Vec<&str> = batch.iter().map(|(file, _)| file.key.as_str()).collect();
+        progress.set_message(keys.join(", "));
+        let objects = fetch_objects(&file_bucket, &keys, timeout_per_file)?;
+        for ((file, local), object) in batch.into_iter().zip(objects) {
+            progress.inc(1);
+            let Some(object) = object else {
+                timed_out.push(file.key);
+                continue;
+            };
+            summary.bytes_downloaded += object.as_slice().len() as u64;
+            let synced = previously_synced.get(&file.key);
+            let remote_unchanged =
+                synced.is_some_and(|synced| synced.remote_unchanged(file.e_tag.as_deref()));
+            let mut remote = RemoteFile::new(file, local, object, config)?;
+
+            let action = if remote.local.exists() {
+                debug!("    Found matching local file: {}", remote.local.display());
+                let metadata = std::fs::metadata(&remote.local)
+                    .context("Could not get metadata for the local file")?;
+                let last_modified_local = OffsetDateTime::from(
+                    metadata
+                        .modified()
+                        .context("Could not read modification time for the local file")?,
+                );
+                debug!(
+                    "    Conflict: Local file: {}, Remote file: {}",
+                    last_modified_local, remote.last_modified
+                );
+                let local_content = std::fs::read(&remote.local)
+                    .context("Error reading the content of the local file")?;
+                if local_content == remote.content.as_slice() {
+                    SyncAction::Identical
+                } else if config.ignore_trailing_newline.unwrap_or(false)
+                    && trim_newlines(&local_content) == trim_newlines(remote.content.as_slice())
+                {
+                    debug!("    Only the trailing newlines differ");
+                    SyncAction::Identical
+                } else if remote
+                    .line_ending
+                    .same_text(&local_content, remote.content.as_slice())
+                {
+                    debug!("    Only the line endings differ");
+                    SyncAction::Identical
+                } else if synced.is_some_and(|synced| synced.local_unchanged(&remote.local)) {
+                    // Compared to the previous sync, only one side changed and there is nothing
+                    // to ask
+                    SyncAction::RemoteChanged
+                } else if remote_unchanged && synced.is_some_and(|synced| synced.local.is_some()) {
+                    SyncAction::LocalChanged
+                } else if let Some(content) =
+                    (!args.only_download && !args.upload_only && !config.is_read_only())
+                        .then(|| merge_with_base(config, &remote, &local_content))
+                        .flatten()
+                {
+                    SyncAction::Merged { content }
+                } else {
+                    SyncAction::Conflict {
+                        local_content,
+                        last_modified_local,
+                    }
+                }
+            } else {
+                SyncAction::Download
+            };
+            match action {
+                SyncAction::Download if write_early && !deleted_locally(&remote.key) => {
+                    retrieve_missing(&remote, &mut local_only, args.verify, &mut summary)?;
+                    in_sync.insert(remote.key);
+                }
+                SyncAction::RemoteChanged if write_early => {
+                    info!("    Changed on the remote only, retrieving {}", remote.key);
+                    remote.download(args.verify)?;
+                    summary.downloaded += 1;
+                    in_sync.insert(remote.key);
+                }
+                _ => {
+                    // Only the files still to write and the conflicts to show need the content
+                    if !matches!(
+                        action,
+                        SyncAction::Download
+                            | SyncAction::RemoteChanged
+                            | SyncAction::Conflict { .. }
+                    ) || args.dry_run
+                        || args.upload_only
+                    {
+                        remote.content = Vec::new();
+                    }
+                    actions.push((remote, action));
+                }
+            }
+        }
+    }
+    progress.finish_and_clear();
+
+    let conflicts: Vec<&str> = actions
+        .iter()
+        .filter(|(_, action)| matches!(action, SyncAction::Conflict { .. }))
+        .map(|(remote, _)| remote.key.as_str())
+        .collect();
+    let conflict_keys: Vec<String> = conflicts.iter().map(|key| key.to_string()).collect();
+    if args.dry_run {
+        for (remote, action) in &actions {
+            match action {
+                SyncAction::Download => {
+                    info!("    Would retrieve {}", remote.key);
+                    summary.downloaded += 1;
+                }
+                SyncAction::Identical => {
+                    info!("    Identical content, skipping: {}", remote.key);
+                    summary.identical += 1;
+                }
+                SyncAction::RemoteChanged => {
+                    info!(
+                        "    Changed on the remote only, would retrieve {}",
+                        remote.key
+                    );
+                    summary.downloaded += 1;
+                }
+                SyncAction::LocalChanged => {
+                    info!("    Changed locally only, would upload {}", remote.key);
+                    summary.uploaded += 1;
+                }
+                SyncAction::Merged { .. } => {
+                    info!("    Changed on both sides, would merge {}", remote.key);
+                    summary.merged += 1;
+                    summary.uploaded += 1;
+                }
+                SyncAction::Conflict { .. } => {
+                    info!("    Would need resolution: {}", remote.key);
+                    summary.unresolved += 1;
+                }
+            }
+        }
+        if args.delete {
+            summary.deleted = delete_removed(roots, config, &remote_keys, true, assume_yes)?;
+        }
+        report_timed_out(&timed_out);
+        return Ok(summary);
+    }
+    if args.abort_on_conflict && !conflicts.is_empty() {
+        if args.download_missing {
+            for (remote, action) in &actions {
+                if let SyncAction::Download = action {
+                    info!("    Local version missing, retrieving {}", remote.key);
+                    remote.download(args.verify)?;
+                }
+            }
+        }
+        run_conflict_hook(config, &conflict_keys)?;
+        bail!(DotError::Conflict(format!(
+            "{} file(s) differ between local and remote:\n    {}",
+            conflicts.len(),
+            conflicts.join("\n    ")
+        )));
+    }
+    let selected: Vec<String> = if args.interactive_select && std::io::stdin().is_terminal() {
+        select_files(&conflicts)
+    } else {
+        conflict_keys.clone()
+    };
+
+    let prompts =
+        !selected.is_empty() && !args.only_download && !args.upload_only && !config.is_read_only();
+    if prompts && strategy.is_none() && !std::io::stdin().is_terminal() {
+        bail!(
+            "{} conflicting file(s) cannot be resolved when not running in a terminal. Use --strategy to resolve them, or --yes to skip them",
+            selected.len()
+        );
+    }
+
+    // Conflicts where one version replaced the other, the others are passed to the hook
+    let mut resolved = HashSet::new();
+    // Conflicts resolved by keeping both versions. The two files still differ, so they are not
+    // recorded as in sync and the next sync asks again
+    let mut kept_both = HashSet::new();
+    // Files written with conflict markers, to edit before the next sync
+    let mut with_markers = vec![];
+    for (remote, action) in actions {
+        match action {
+            SyncAction::Download if args.upload_only => {
+                warn!("    Missing locally, skipping: {}", remote.key)
+            }
+            SyncAction::RemoteChanged if args.upload_only => {
+                info!("    Changed on the remote only, keeping: {}", remote.key)
+            }
+            SyncAction::Conflict { .. } if args.upload_only => {
+                info!("    Uploading the local version of {}", remote.key);
+                if let Some(bytes) =
+                    transfer_file(&remote.key, timeout_per_file, &mut timed_out, || {
+                        upload_local_file(&remote.local, &remote.key, &file_bucket, config)
+                    })?
+                {
+                    summary.uploaded += 1;
+                    summary.bytes_uploaded += bytes;
+                    resolved.insert(remote.key);
+                }
+            }
+            SyncAction::Download => {
+                if deleted_locally(&remote.key) {
+                    info!("    {} was deleted locally since the last sync", remote.key);
+                    match ask_user(
+                        "Delete (d) the remote version, Restore (r) the local file, or Skip (s) this file",
+                        vec!["d", "r", "s"],
+                    )
+                    .as_str()
+                    {
+                        "d" => {
+                            remote::delete(&bucket, &remote.key)?;
+                            info!("    Deleted {} from the remote", remote.key);
+                            remote_keys.remove(&remote.key);
+                            summary.deleted_remote += 1;
+                            continue;
+                        }
+                        "s" => continue,
+                        _ => {}
+                    }
+                }
+                retrieve_missing(&remote, &mut local_only, args.verify, &mut summary)?;
+                in_sync.insert(remote.key);
+            }
+            SyncAction::Identical => {
+                info!("    Identical content, skipping: {}", remote.key);
+                summary.identical += 1;
+                in_sync.insert(remote.key);
+            }
+            SyncAction::RemoteChanged => {
+                info!("    Changed on the remote only, retrieving {}", remote.key);
+                remote.download(args.verify)?;
+                summary.downloaded += 1;
+                in_sync.insert(remote.key);
+            }
+            SyncAction::LocalChanged if args.only_download || config.is_read_only() => {
+                info!("    Changed locally only, keeping: {}", remote.key)
+            }
+            SyncAction::LocalChanged => {
+                if let Some(bytes) =
+                    transfer_file(&remote.key, timeout_per_file, &mut timed_out, || {
+                        upload_local_file(&remote.local, &remote.key, &file_bucket, config)
+                    })?
+                {
+                    summary.uploaded += 1;
+                    summary.bytes_uploaded += bytes;
+                    in_sync.insert(remote.key);
+                }
+            }
+            SyncAction::Merged { content } => {
+                info!("    Changed on both sides, merging {}", remote.key);
+                replace_local_file(&remote.local, &content, SystemTime::now(), remote.backup)?;
+                if let Some(bytes) =
+                    transfer_file(&remote.key, timeout_per_file, &mut timed_out, || {
+                        upload_local_file(&remote.local, &remote.key, &file_bucket, config)
+                    })?
+                {
+                    summary.merged += 1;
+                    summary.uploaded += 1;
+                    summary.bytes_uploaded += bytes;
+                    in_sync.insert(remote.key);
+                }
+            }
+            SyncAction::Conflict { .. } if !selected.contains(&remote.key) => {
+                info!("    Not selected, skipping: {}", remote.key)
+            }
+            SyncAction::Conflict {
+                last_modified_local,
+                ..
+            } if args.only_download || config.is_read_only() => {
+                if last_modified_local > remote.last_modified && !args.force {
+                    warn!(
+                        "    Local version is newer, skipping: {} (use --force to overwrite it)",
+                        remote.key
+                    );
+                    continue;
+                }
+                info!("    Overwriting local version of {}", remote.key);
+                remote.download(args.verify)?;
+                summary.downloaded += 1;
+                resolved.insert(remote.key);
+            }
+            SyncAction::Conflict {
+                local_content,
+                last_modified_local,
+            } => {
+                if args.explain_conflict {
+                    info!(
+                        "    {} - Local: modified {}, {}. Remote: modified {}, {}. The {} version is newer",
+                        remote.key,
+                        last_modified_local,
+                        format_size(local_content.len() as u64),
+                        remote.last_modified,
+                        format_size(remote.size),
+                        if last_modified_local > remote.last_modified {
+                            "local"
+                        } else {
+                            "remote"
+                        }
+                    );
+                }
+                // Binary files are compared byte for byte, without a diff to show
+                let texts = as_text(&local_content).zip(as_text(remote.content.as_slice()));
+                match texts {
+                    Some((local_text, remote_text)) => {
+                        let (patch, header) = match args.diff_direction {
+                            DiffDirection::LocalToRemote => (
+                                diffy::create_patch(local_text, remote_text),
+                                "Original is local, Modified is remote",
+                            ),
+                            DiffDirection::RemoteToLocal => (
+                                diffy::create_patch(remote_text, local_text),
+                                "Original is remote, Modified is local",
+                            ),
+                        };
+                        let patch_fmt = PatchFormatter::new().with_color();
+                        info!(
+                            "    {} - {}:\n{}",
+                            remote.key,
+                            header,
+                            patch_fmt.fmt_patch(&patch)
+                        );
+                    }
+                    None => info!("    {} - The binary files differ", remote.key),
+                }
+                let response = match strategy {
+                    Some(strategy) => {
+                        info!("    Resolving {} with {:?}", remote.key, strategy);
+                        strategy.answer().to_string()
+                    }
+                    None if texts.is_none() => ask_user(
+                        "Upload (u) local version, Overwrite (o) local version with remote, Skip (s) this file, or Exit (e)",
+                        vec!["u", "o", "s", "e"],
+                    ),
+                    None => ask_user("Upload (u) local version, Overwrite (o) local version with remote, keep both by Renaming (r) the remote version, Merge (m) them with $MERGE_TOOL, write Conflict markers (c) in the local file, Skip (s) this file, or Exit (e)", vec!["u", "o", "r", "m", "c", "s", "e"]),
+                };
+                match response.as_str() {
+                    "u" => {
+                        if let Some(bytes) =
+                            transfer_file(&remote.key, timeout_per_file, &mut timed_out, || {
+                                upload_local_file(&remote.local, &remote.key, &file_bucket, config)
+                            })?
+                        {
+                            summary.uploaded += 1;
+                            summary.bytes_uploaded += bytes;
+                            resolved.insert(remote.key);
+                        }
+                    }
+                    "o" => {
+                        remote.download(args.verify)?;
+                        summary.downloaded += 1;
+                        resolved.insert(remote.key);
+                    }
+                    "r" => {
+                        let renamed = RemoteFile {
+                            local: remote.local.with_file_name(format!(
+                                "{}.remote",
+                                remote
+                                    .local
+                                    .file_name()
+                                    .context("Invalid local file name")?
+                                    .to_string_lossy()
+                            )),
+                            ..remote
+                        };
+                        info!("    Remote version saved as {}", renamed.local.display());
+                        renamed.download(args.verify)?;
+                        summary.downloaded += 1;
+                        kept_both.insert(renamed.key);
+                    }
+                    "m" => {
+                        let Some(merged) = run_merge_tool(&remote, &local_content)? else {
+                            warn!("    The merge tool failed, skipping: {}", remote.key);
+                            continue;
+                        };
+                        replace_local_file(
+                            &remote.local,
+                            &merged,
+                            SystemTime::now(),
+                            remote.backup,
+                        )?;
+                        if let Some(bytes) =
+                            transfer_file(&remote.key, timeout_per_file, &mut timed_out, || {
+                                upload_local_file(&remote.local, &remote.key, &file_bucket, config)
+                            })?
+                        {
+                            summary.merged += 1;
+                            summary.uploaded += 1;
+                            summary.bytes_uploaded += bytes;
+                            resolved.insert(remote.key);
+                        }
+                    }
+                    "c" => {
+                        let content = with_conflict_markers(config, &remote, &local_content)
+                            .context("Conflict markers can only be written in text files")?;
+                        replace_local_file(
+                            &remote.local,
+                            &content,
+                            SystemTime::now(),
+                            remote.backup,
+                        )?;
+                        info!("    Conflict markers written in {}", remote.local.display());
+                        with_markers.push(remote.local);
+                    }
+                    "s" => continue,
+                    "e" => {
+                        report_timed_out(&timed_out);
+                        summary.failed = timed_out.len();
+                        return Ok(summary);
+                    }
+                    _ => bail!("Unknown action"),
+                }
+            }
+        }
+    }
+    if !with_markers.is_empty() {
+        warn!(
+            "{} file(s) left with conflict markers, edit them and sync again to upload them:\n    {}",
+            with_markers.len(),
+            with_markers
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join("\n    ")
+        );
+    }
+    if args.delete {
+        summary.deleted = delete_removed(roots, config, &remote_keys, false, assume_yes)?;
+    }
+    if let Some(path) = &config.synced_keys_file {
+        let base_dir = synced::base_dir(path);
+        let mut state = BTreeMap::new();
+        for (key, etag) in &remote_keys {
+            let synced = match local_path(roots, &config.original_key(key)) {
+                _ if not_selected.contains(key) => {
+                    previously_synced.get(key).cloned().unwrap_or_default()
+                }
+                Some(local) if in_sync.contains(key) || resolved.contains(key) => {
+                    synced::save_base(&base_dir, key, &local, previously_synced.get(key))?;
+                    SyncedFile::in_sync(etag.clone(), &local)
+                }
+                _ => SyncedFile::default(),
+            };
+            state.insert(key.clone(), synced);
+        }
+        synced::save(path, &state)?;
+    }
+    report_timed_out(&timed_out);
+    summary.failed = timed_out.len();
+    let (resolved, unresolved): (Vec<String>, Vec<String>) = conflict_keys
+        .into_iter()
+        .partition(|key| resolved.contains(key) || kept_both.contains(key));
+    summary.resolved = resolved.len();
+    if !unresolved.is_empty() {
+        run_conflict_hook(config, &unresolved)?;
+    }
+    summary.unresolved = unresolved.len();
+    Ok(summary)
+}
+
+/// Delete the local files of the keys that were on the remote at the previous sync but are not
+/// in `remote_keys` anymore. Only the files of these keys are considered, so that the files
+/// never synced are left alone. Returns the number of files deleted, or that would be with
+/// `dry_run`.
+fn delete_removed(
+    roots: &[Root],
+    config: &Config,
+    remote_keys: &BTreeMap<String, Option<String>>,
+    dry_run: bool,
+    assume_yes: bool,
+) -> Result<usize> {
+    let Some(path) = &config.synced_keys_file else {
+        bail!("--delete needs a configuration file, the synced keys are saved next to it");
+    };
+    let mut deleted = 0;
+    // Only the keys written locally by a previous sync, the others may share their path with
+    // an unrelated local file
+    for key in synced::load(path)?
+        .iter()
+        .filter(|(key, synced)| synced.local.is_some() && !remote_keys.contains_key(*key))
+        .map(|(key, _)| key)
+    {
+        if !is_safe_key(key) {
+            continue;
+        }
+        let Some(local) =
+            local_path(roots, &config.original_key(key)).filter(|local| local.is_file())
+        else {
+            continue;
+        };
+        if dry_run {
+            info!(
+                "    Deleted on the remote, would delete {}",
+                local.display()
+            );
+        } else if confirm(
+            &format!("{} was deleted on the remote, delete it?", local.display()),
+            assume_yes,
+        )? {
+            info!("    Deleted on the remote, deleting {}", local.display());
+            fs::remove_file(&local)
+                .with_context(|| format!("Could not delete {}", local.display()))?;
+        } else {
+            continue;
+        }
+        deleted += 1;
+    }
+    Ok(deleted)
+}
+
+/// Run the `on_conflict` command of the configuration, if any, with the keys of the
+/// conflicting files in `DOT_CONFLICTS`, one per line. A failing command is only reported.
+fn run_conflict_hook(config: &Config, keys: &[String]) -> Result<()> {
+    let Some(command) = &config.on_conflict else {
+        return Ok(());
+    };
+    info!("Running the on_conflict hook for {} file(s)", keys.len());
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let status = std::process::Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("DOT_CONFLICTS", keys.join("\n"))
+        .status()
+        .with_context(|| format!("Could not run the on_conflict hook {}", command))?;
+    if !status.success() {
+        warn!("The on_conflict hook {} failed: {}", command, status);
+    }
+    Ok(())
+}
+
+/// Merge the local and remote versions of a text file that both changed since the previous
+/// sync, from the copy of the file kept at the end of it. `None` if there is no copy or if the
+/// changes overlap.
+fn merge_with_base(config: &Config, remote: &RemoteFile, local_content: &[u8]) -> Option<Vec<u8>> {
+    let base = base_version(config, &remote.key)?;
+    // The copy is of the local file, with its line endings
+    let remote_content = remote.line_ending.convert(&remote.content);
+    let merged = diffy::merge(
+        as_text(&base)?,
+        as_text(local_content)?,
+        as_text(&remote_content)?,
+    )
+    .ok()?;
+    Some(merged.into_bytes())
+}
+
+/// The local and remote versions of a text file merged with the `<<<<<<<`, `=======` and
+/// `>>>>>>>` markers around the changes that overlap, as git does. Without a copy of the file
+/// from the previous sync, the whole file is one conflict. `None` for binary files.
+fn with_conflict_markers(
+    config: &Config,
+    remote: &RemoteFile,
+    local_content: &[u8],
+) -> Option<Vec<u8>> {
+    let base = base_version(config, &remote.key).unwrap_or_default();
+    let remote_content = remote.line_ending.convert(&remote.content);
+    // The merge fails with the content and its markers when the changes overlap
+    let merged = diffy::merge(
+        as_text(&base).unwrap_or_default(),
+        as_text(local_content)?,
+        as_text(&remote_content)?,
+    )
+    .unwrap_or_else(|with_markers| with_markers);
+    Some(merged.into_bytes())
+}
+
+/// Copy of `key` kept at the end of the previous sync
+fn base_version(config: &Config, key: &str) -> Option<Vec<u8>> {
+    synced::load_base(&synced::base_dir(config.synced_keys_file.as_ref()?), key)
+}
+
+/// Let the user merge the two versions of a conflicting file with the command in `MERGE_TOOL`,
+/// `vimdiff` by default, called with a copy of the local and of the remote version. The copy of
+/// the local version is the result of the merge. `None` if the tool exits with an error.
+fn run_merge_tool(remote: &RemoteFile, local_content: &[u8]) -> Result<Option<Vec<u8>>> {
+    let tool = std::env::var("MERGE_TOOL").unwrap_or_else(|_| "vimdiff".to_string());
+    let mut words = tool.split_whitespace();
+    let program = words.next().context("MERGE_TOOL is empty")?;
+    let name = remote
+        .local
+        .file_name()
+        .context("Invalid local file name")?
+        .to_string_lossy();
+    let dir = std::env::temp_dir();
+    let local_copy = dir.join(format!("{}.{}.local", name, std::process::id()));
+    let remote_copy = dir.join(format!("{}.{}.remote", name, std::process::id()));
+    write_private(&local_copy, local_content)?;
+    if let Err(err) = write_private(&remote_copy, &remote.line_ending.convert(&remote.content)) {
+        let _ = fs::remove_file(&local_copy);
+        return Err(err);
+    }
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(&local_copy)
+        .arg(&remote_copy)
+        .status();
+    let merged = fs::read(&local_copy);
+    let _ = fs::remove_file(&local_copy);
+    let _ = fs::remove_file(&remote_copy);
+    if !status
+        .with_context(|| format!("Could not run the merge tool {}", tool))?
+        .success()
+    {
+        return Ok(None);
+    }
+    Ok(Some(merged.context("Error reading the merged file")?))
+}
+
+/// Write `content` to the new file `path`, only readable by the user. Fails if `path` already
+/// exists, so that a file or a link planted in a shared directory is never written through.
+fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(content))
+        .with_context(|| format!("Error writing {}", path.display()))
+}
+
+/// `content` without the newlines at its end
+fn trim_newlines(mut content: &[u8]) -> &[u8] {
+    while let [rest @ .., b'\n'] = content {
+        content = rest;
+    }
+    content
+}
+
+/// Index in `local_only` of a file with the same content as `remote`, which is then most
+/// likely the previous location of the remote file. Only the files of the same size are read.
+/// Write `remote`, missing locally. A file of `local_only` with the same content is moved
+/// instead, it was renamed on the remote.
+fn retrieve_missing(
+    remote: &RemoteFile,
+    local_only: &mut Vec<PathBuf>,
+    verify: bool,
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    if let Some(index) = find_renamed(local_only, remote)? {
+        let renamed = local_only.swap_remove(index);
+        info!(
+            "    Renamed on the remote, moving {} to {}",
+            renamed.display(),
+            remote.local.display()
+        );
+        if let Some(parent) = remote.local.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Cannot create the directory {}", parent.display()))?;
+        }
+        fs::rename(&renamed, &remote.local).with_context(|| {
+            format!(
+                "Could not move {} to {}",
+                renamed.display(),
+                remote.local.display()
+            )
+        })?;
+        summary.moved += 1;
+        return Ok(());
+    }
+    info!("    Local version missing, retrieving {}", remote.key);
+    remote.download(verify)?;
+    summary.downloaded += 1;
+    Ok(())
+}
+
+fn find_renamed(local_only: &[PathBuf], remote: &RemoteFile) -> Result<Option<usize>> {
+    let content = remote.content.as_slice();
+    for (index, path) in local_only.iter().enumerate() {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("Could not stat {}", path.display()))?;
+        if metadata.len() != content.len() as u64 {
+            continue;
+        }
+        if fs::read(path).with_context(|| format!("Error reading {}", path.display()))? == content {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+/// Retrieve the content of `keys` in parallel, one thread per key. A key whose transfer timed
+/// out has no content, as with `transfer_file`.
+fn fetch_objects(
+    bucket: &Bucket,
+    keys: &[&str],
+    timeout: Option<Duration>,
+) -> Result<Vec<Option<ResponseData>>> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = keys
+            .iter()
+            .map(|&key| {
+                let bucket = bucket.clone();
+                scope.spawn(move || {
+                    transfer_file(key, timeout, &mut vec![], || {
+                        retry::response(key, || bucket.get_object(key))
+                            .with_context(|| format!("Could not retrieve file {} from S3", key))
+                    })
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("A download thread panicked")))
+            })
+            .collect()
+    })
+}
+
+/// Copy of `bucket` used for the file transfers, whose requests are abandoned after `timeout`
+fn with_file_timeout(bucket: &Bucket, timeout: Option<Duration>) -> Bucket {
+    let mut bucket = bucket.clone();
+    if timeout.is_some() {
+        bucket.set_request_timeout(timeout);
+    }
+    bucket
+}
+
+/// Run the transfer of a single file, without retrying its requests after `timeout`. If it
+/// fails after `timeout`, the key is added to `timed_out` and `None` is returned so that the
+/// caller can carry on with the next file.
+fn transfer_file<T>(
+    key: &str,
+    timeout: Option<Duration>,
+    timed_out: &mut Vec<String>,
+    transfer: impl FnOnce() -> Result<T>,
+) -> Result<Option<T>> {
+    let start = Instant::now();
+    let result = retry::until(timeout.map(|timeout| start + timeout), transfer);
+    match timeout {
+        Some(timeout) if result.is_err() && start.elapsed() >= timeout => {
+            warn!("Timed out after {}s, skipping: {}", timeout.as_secs(), key);
+            timed_out.push(key.to_string());
+            Ok(None)
+        }
+        _ => result.map(Some),
+    }
+}
+
+fn report_timed_out(timed_out: &[String]) {
+    if !timed_out.is_empty() {
+        warn!(
+            "{} file(s) timed out and were skipped:\n    {}",
+            timed_out.len(),
+            timed_out.join("\n    ")
+        );
+    }
+}
+
+/// Object metadata storing the modification time of the file when it was uploaded, in
+/// nanoseconds since the unix epoch
+const MTIME_METADATA: &str = "x-amz-meta-mtime";
+
+/// Check if the remote version of `key` was modified after the local file `path`, in which
+/// case uploading `path` would lose the remote changes. False when the credentials are not
+/// allowed to read the metadata of `key`, so that the file is still uploaded.
+fn remote_is_newer(bucket: &Bucket, key: &str, path: &Path) -> Result<bool> {
+    let remote_modified = match remote_modified(bucket, key) {
+        Err(err)
+            if matches!(
+                err.downcast_ref::<DotError>(),
+                Some(DotError::Unauthorized(_))
+            ) =>
+        {
+            warn!(
+                "{:#}. Cannot tell if the remote version is newer, uploading {}",
+                err, key
+            );
+            return Ok(false);
+        }
+        result => result?,
+    };
+    let Some(remote_modified) = remote_modified else {
+        return Ok(false);
+    };
+    let local_modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .context("Could not read modification time for the local file")?;
+    Ok(remote_modified > OffsetDateTime::from(local_modified))
+}
+
+/// Modification time of the remote `key` from its metadata, without retrieving its content:
+/// the original mtime if it was stored, the upload time otherwise. `None` if it does not exist.
+fn remote_modified(bucket: &Bucket, key: &str) -> Result<Option<OffsetDateTime>> {
+    let Some(stat) = remote::stat(bucket, key)? else {
+        return Ok(None);
+    };
+    let original = stat
+        .metadata
+        .get(MTIME_METADATA.trim_start_matches("x-amz-meta-"))
+        .and_then(|nanos| nanos.parse::<i128>().ok())
+        .and_then(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).ok());
+    Ok(Some(original.unwrap_or(stat.last_modified)))
+}
+
+/// Check if the local file `path` has the content of the remote `file` without retrieving it.
+/// The MD5 of the content is compared with the ETag, which is only possible for the objects
+/// uploaded in a single part. With `compare = "hash"` and another algorithm, the hash stored in
+/// the metadata at upload time is compared too, retrieved with a HEAD request.
+fn same_hash(bucket: &Bucket, file: &Object, path: &Path, config: &Config) -> Result<bool> {
+    if !fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == file.size) {
+        return Ok(false);
+    }
+    let etag = file
+        .e_tag
+        .as_deref()
+        .map(|etag| etag.trim_matches('"'))
+        .filter(|etag| !etag.contains('-'));
+    let algorithm = config.hash.unwrap_or_default();
+    let stored =
+        if config.compare.unwrap_or_default() == Compare::Hash && algorithm != HashAlgorithm::Md5 {
+            let (head, _) = bucket
+                .head_object(&file.key)
+                .with_context(|| format!("Could not get the metadata of {} from S3", file.key))?;
+            head.metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(HASH_METADATA.trim_start_matches("x-amz-meta-")))
+                .and_then(|stored| stored.strip_prefix(&format!("{}:", algorithm.name())))
+                .map(str::to_string)
+        } else {
+            None
+        };
+    if etag.is_none() && stored.is_none() {
+        return Ok(false);
+    }
+    let content = fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+    Ok(
+        etag.is_some_and(|etag| HashAlgorithm::Md5.hash_bytes(&content) == etag)
+            || stored.is_some_and(|hash| algorithm.hash_bytes(&content) == hash),
+    )
+}
+
+/// Check if the local file `path` is the one written by the last download of the remote
+/// `file`: same size, and a modification time within a second of the remote one. This avoids
+/// retrieving the content of the files that did not change on either side.
+fn unchanged_since_download(
+    bucket: &Bucket,
+    file: &Object,
+    path: &Path,
+    config: &Config,
+) -> Result<bool> {
+    let Some(metadata) = fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+    else {
+        return Ok(false);
+    };
+    if metadata.len() != file.size {
+        return Ok(false);
+    }
+    let local_modified = OffsetDateTime::from(
+        metadata
+            .modified()
+            .context("Could not read modification time for the local file")?,
+    );
+    // The downloads are given the original mtime, only available from the object metadata
+    let remote_modified = if config.preserve_mtime.unwrap_or(true) {
+        match remote_modified(bucket, &file.key)? {
+            Some(remote_modified) => remote_modified,
+            None => return Ok(false),
+        }
+    } else {
+        OffsetDateTime::parse(&file.last_modified, &Rfc3339)
+            .context("Error parsing the file modification date from the aws s3 header")?
+    };
+    Ok((local_modified - remote_modified).abs() < time::Duration::SECOND)
+}
+
+/// Modification time of the file when it was uploaded, if it was stored with the object
+fn original_mtime(object: &ResponseData) -> Option<OffsetDateTime> {
+    let nanos = object.headers().get(MTIME_METADATA)?.parse::<i128>().ok()?;
+    OffsetDateTime::from_unix_timestamp_nanos(nanos).ok()
+}
+
+fn upload_local_file(
+    file_path: &Path,
+    bucket_key: &str,
+    bucket: &Bucket,
+    config: &Config,
+) -> Result<u64> {
+    info!("Uploading {} to {}", file_path.display(), bucket_key);
+    let is_dir = file_path.is_dir();
+    let size = std::fs::metadata(file_path)
+        .context("Could not get metadata for the file to upload")?
+        .len();
+    if let Some(max_upload_size) = config.max_upload_size.filter(|_| !is_dir) {
+        if size > max_upload_size {
+            bail!(
+                "The file {} is {} bytes, more than the max_upload_size of {} bytes",
+                file_path.display(),
+                size,
+                max_upload_size
+            );
+        }
+    }
+    let mut bucket = bucket.clone();
+    if config.preserve_mtime.unwrap_or(true) {
+        let modified = std::fs::metadata(file_path)
+            .and_then(|metadata| metadata.modified())
+            .context("Could not read modification time for the local file")?;
+        bucket.add_header(
+            MTIME_METADATA,
+            &OffsetDateTime::from(modified)
+                .unix_timestamp_nanos()
+                .to_string(),
+        );
+    }
+    if config.preserve_xattrs.unwrap_or(false) {
+        if let Some(encoded) = xattrs::encode(file_path)? {
+            bucket.add_header(XATTRS_METADATA, &encoded);
+        }
+    }
+    let algorithm = config.hash.unwrap_or_default();
+    let hash_metadata =
+        config.compare.unwrap_or_default() == Compare::Hash && algorithm != HashAlgorithm::Md5;
+    // The large files are read while they are sent, unless their whole content is needed first
+    if !is_dir
+        && size > STREAM_THRESHOLD
+        && config.upload_filter(bucket_key).is_none()
+        && !hash_metadata
+    {
+        let mut reader = fs::File::open(file_path).context("Error reading file to upload")?;
+        let status_code = bucket
+            .put_object_stream(&mut reader, bucket_key)
+            .with_context(|| {
+                format!(
+                    "Error uploading file {} to the S3 bucket {}:{}",
+                    file_path.display(),
+                    bucket.name,
+                    bucket_key
+                )
+            })?;
+        return match status_code {
+            200 => Ok(size),
+            403 => bail!(DotError::Unauthorized("Upload failed with error 403: Forbidden. Please check that your credentials allows you to upload files to the S3 bucket".to_string())),
+            err => bail!(DotError::from_status(err, format!("Upload failed with error code {}", err)))
+        };
+    }
+    let data = if is_dir {
+        vec![]
+    } else {
+        std::fs::read(file_path).context("Error reading file to upload")?
+    };
+    let data = match config.upload_filter(bucket_key).filter(|_| !is_dir) {
+        Some(command) => filter::run(command, bucket_key, &data)?,
+        None => data,
+    };
+    if hash_metadata && !is_dir {
+        bucket.add_header(HASH_METADATA, &algorithm.metadata(&data));
+    }
+    let response = retry::response(bucket_key, || bucket.put_object(bucket_key, &data))
+        .with_context(|| {
+            format!(
+                "Error uploading file {} to the S3 bucket {}:{}",
+                file_path.display(),
+                bucket.name,
+                bucket_key
+            )
+        })?;
+    // I guess that's a bug from the s3 crate that isn't propagating errors from the http library.
     match response.status_code() {
         // The only valid status code
         // https://docs.aws.amazon.com/AmazonS3/latest/API/API_PutObject.html
-        200 => Ok(()),
-        403 => bail!("Upload failed with error 403: Forbidden. Please check that your credentials allows you to upload files to the S3 bucket"),
-        err => bail!("Upload failed with error code {}", err)
+        200 => Ok(data.len() as u64),
+        403 => bail!(DotError::Unauthorized("Upload failed with error 403: Forbidden. Please check that your credentials allows you to upload files to the S3 bucket".to_string())),
+        err => bail!(DotError::from_status(err, format!("Upload failed with error code {}", err)))
     }
 }
 
-fn replace_local_file(path: &Path, content: &[u8], modified_time: SystemTime) -> Result<()> {
+/// Files larger than this are written to disk by pull while they are retrieved, and read from
+/// disk by the uploads while they are sent, instead of being held in memory
+const STREAM_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// Retrieve the remote `file` straight into the local file `path`, without holding its content
+/// in memory. Only for the files written as they are on the remote, without a download filter
+/// or a line ending conversion. The content goes to `<path>.dot-part` first, so that `path` is
+/// left as it was if the transfer fails.
+fn stream_to_local_file(
+    bucket: &Bucket,
+    file: &Object,
+    path: &Path,
+    config: &Config,
+) -> Result<()> {
+    let stat = remote::stat(bucket, &file.key)?
+        .with_context(|| format!("{} was deleted from the remote", file.key))?;
+    remote::check_content_type(&file.key, stat.content_type.as_deref())?;
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent)
             .with_context(|| format!("Cannot create intermediate directory {}", parent.display()))?
     }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".dot-part");
+    let partial = PathBuf::from(partial);
+    let mut writer = fs::File::create(&partial)
+        .with_context(|| format!("Could not create {}", partial.display()))?;
+    let transfer = bucket
+        .get_object_to_writer(&file.key, &mut writer)
+        .with_context(|| format!("Could not retrieve file {} from S3", file.key))
+        .and_then(|status_code| match status_code {
+            200 => Ok(()),
+            err => bail!(DotError::from_status(
+                err,
+                format!("Could not retrieve file {}, error code {}", file.key, err)
+            )),
+        });
+    drop(writer);
+    if let Err(err) = transfer {
+        let _ = fs::remove_file(&partial);
+        return Err(err);
+    }
+    if config.backup.unwrap_or(false) {
+        backup_local_file(path)?;
+    }
+    fs::rename(&partial, path).context("Error updating the local file")?;
+    let modified = stat
+        .metadata
+        .get(MTIME_METADATA.trim_start_matches("x-amz-meta-"))
+        .filter(|_| config.preserve_mtime.unwrap_or(true))
+        .and_then(|nanos| nanos.parse::<i128>().ok())
+        .and_then(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).ok())
+        .unwrap_or(stat.last_modified);
+    let modified = FileTime::from_system_time(SystemTime::from(modified));
+    set_file_times(path, modified, modified)
+        .context("Error when updating the time for the downloaded file")?;
+    if config.preserve_xattrs.unwrap_or(false) {
+        if let Some(encoded) = stat
+            .metadata
+            .get(XATTRS_METADATA.trim_start_matches("x-amz-meta-"))
+        {
+            xattrs::apply(path, &xattrs::decode(encoded));
+        }
+    }
+    Ok(())
+}
 
+/// Copy `path`, if it exists, to `<path>.dot-bak`, replacing the previous backup
+fn backup_local_file(path: &Path) -> Result<()> {
+    if !path.is_file() {
+        return Ok(());
+    }
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(".dot-bak");
+    fs::copy(path, &backup_path)
+        .with_context(|| format!("Could not back up {} before replacing it", path.display()))?;
+    info!(
+        "    Previous version of {} saved as {}",
+        path.display(),
+        Path::new(&backup_path).display()
+    );
+    Ok(())
+}
+
+/// Write `content` to `path`. With `backup`, an existing file is first copied to
+/// `<path>.dot-bak`, replacing the previous backup.
+fn replace_local_file(
+    path: &Path,
+    content: &[u8],
+    modified_time: SystemTime,
+    backup: bool,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create intermediate directory {}", parent.display()))?
+    }
+    if backup {
+        backup_local_file(path)?;
+    }
     std::fs::write(path, content).context("Error updating the local file")?;
     let last_modified_s3 = FileTime::from_system_time(modified_time);
     set_file_times(path, last_modified_s3, last_modified_s3)
         .context("Error when updating the time for the downloaded file")
 }
 
+/// Let the user pick a subset of `keys`. Returns the selected keys.
+fn select_files(keys: &[&str]) -> Vec<String> {
+    if keys.is_empty() {
+        return vec![];
+    }
+    println!("Conflicting files:");
+    for (index, key) in keys.iter().enumerate() {
+        println!("  {:>3}) {}", index + 1, key);
+    }
+    loop {
+        print!("Files to resolve (space-separated numbers, 'a' for all, empty for none): ");
+        std::io::stdout().flush().unwrap_or_default();
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).unwrap();
+        let line = line.trim();
+        if line == "a" {
+            return keys.iter().map(|key| key.to_string()).collect();
+        }
+        let indices: Option<Vec<usize>> = line
+            .split_whitespace()
+            .map(|n| {
+                n.parse::<usize>()
+                    .ok()
+                    .filter(|n| (1..=keys.len()).contains(n))
+            })
+            .collect();
+        if let Some(indices) = indices {
+            return indices.iter().map(|n| keys[n - 1].to_string()).collect();
+        }
+    }
+}
+
+/// Ask the user to confirm an action, unless `assume_yes` is set
+fn confirm(prompt: &str, assume_yes: bool) -> Result<bool> {
+    if assume_yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "{} Use --yes to confirm when not running in a terminal",
+            prompt
+        );
+    }
+    Ok(ask_user(&format!("{} ", prompt), vec!["y", "n"]) == "y")
+}
+
 fn ask_user(prompt: &str, accepted_values: Vec<&str>) -> String {
     print!("{}", prompt);
+    // In a terminal a single keystroke is enough, piped input is still read line by line
+    if std::io::stdin().is_terminal() && accepted_values.iter().all(|value| value.len() == 1) {
+        print!("[{}]: ", accepted_values.join(", "));
+        std::io::stdout().flush().unwrap_or_default();
+        if let Some(key) = read_key(&accepted_values) {
+            println!("{}", key);
+            return key;
+        }
+    }
     let mut line = String::new();
     while !accepted_values.contains(&line.trim()) {
         print!("input [{}]: ", accepted_values.join(", "));
@@ -387,3 +2720,221 @@ fn ask_user(prompt: &str, accepted_values: Vec<&str>) -> String {
     }
     return line.trim().to_owned();
 }
+
+/// Read keystrokes in raw mode until one of `accepted_values` is pressed. Returns `None` if
+/// the terminal cannot be switched to raw mode, exits on Ctrl-C since raw mode disables the
+/// signal.
+fn read_key(accepted_values: &[&str]) -> Option<String> {
+    terminal::enable_raw_mode().ok()?;
+    let key = loop {
+        let Some(event) = event::read().ok() else {
+            break None;
+        };
+        let Event::Key(KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event
+        else {
+            continue;
+        };
+        if c == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
+            terminal::disable_raw_mode().unwrap_or_default();
+            println!();
+            std::process::exit(130);
+        }
+        let key = c.to_string();
+        if accepted_values.contains(&key.as_str()) {
+            break Some(key);
+        }
+    };
+    terminal::disable_raw_mode().unwrap_or_default();
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_keys_outside_of_the_root() {
+        assert!(is_safe_key(".bashrc"));
+        assert!(is_safe_key(".config/git/config"));
+        assert!(is_safe_key("./.vimrc"));
+        assert!(!is_safe_key("../../.ssh/authorized_keys"));
+        assert!(!is_safe_key(".config/../../.ssh/authorized_keys"));
+        assert!(!is_safe_key("/etc/passwd"));
+    }
+
+    fn roots_of(roots: &[(&str, &str)]) -> Vec<Root> {
+        roots
+            .iter()
+            .map(|(path, prefix)| Root {
+                path: PathBuf::from(path),
+                prefix: prefix.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn local_path_uses_the_longest_prefix() {
+        let roots = roots_of(&[
+            ("/home/me", ""),
+            ("/etc/app", "app/"),
+            ("/srv/conf", "app/conf/"),
+        ]);
+        assert_eq!(
+            local_path(&roots, ".bashrc"),
+            Some(PathBuf::from("/home/me/.bashrc"))
+        );
+        assert_eq!(
+            local_path(&roots, "app/settings"),
+            Some(PathBuf::from("/etc/app/settings"))
+        );
+        assert_eq!(
+            local_path(&roots, "app/conf/main.toml"),
+            Some(PathBuf::from("/srv/conf/main.toml"))
+        );
+        let roots = roots_of(&[("/etc/app", "app/")]);
+        assert_eq!(local_path(&roots, ".bashrc"), None);
+    }
+
+    #[test]
+    fn find_root_picks_the_innermost_root() {
+        let roots = roots_of(&[("/home/me", ""), ("/home/me/.config", "config/")]);
+        let (path, root) = find_root(&roots, Path::new("/home/me/.config/git/config")).unwrap();
+        assert_eq!(path, PathBuf::from("/home/me/.config/git/config"));
+        assert_eq!(root.prefix, "config/");
+        let (_, root) = find_root(&roots, Path::new("/home/me/.bashrc")).unwrap();
+        assert_eq!(root.prefix, "");
+        assert!(find_root(&roots, Path::new("/nonexistent/file")).is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn find_root_behind_a_symlink() {
+        let dir = std::env::temp_dir().join(format!("dotfile-test-symlink-{}", std::process::id()));
+        let real = dir.join("real");
+        let link = dir.join("link");
+        fs::create_dir_all(real.join(".config/git")).unwrap();
+        fs::write(real.join(".config/git/config"), "[user]\n").unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        // The file is given through its resolved path, the key comes from the root as
+        // configured
+        let roots = roots_of(&[(link.to_str().unwrap(), "")]);
+        let found = find_root(&roots, &real.join(".config/git/config"))
+            .map(|(path, root)| (path, root.path.clone()));
+        // And the other way around
+        let roots = roots_of(&[(real.to_str().unwrap(), "")]);
+        let found_through_link = find_root(&roots, &link.join(".config/git/config"))
+            .map(|(path, root)| (path, root.path.clone()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (path, root) = found.unwrap();
+        assert_eq!(path, link.join(".config/git/config"));
+        assert_eq!(
+            path.strip_prefix(root).unwrap(),
+            Path::new(".config/git/config")
+        );
+        let (path, root) = found_through_link.unwrap();
+        assert_eq!(
+            path.strip_prefix(root).unwrap(),
+            Path::new(".config/git/config")
+        );
+    }
+
+    #[test]
+    fn upload_refuses_files_over_max_upload_size() {
+        let dir =
+            std::env::temp_dir().join(format!("dotfile-test-max-upload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large");
+        fs::write(&path, vec![0; 2048]).unwrap();
+        // Nothing listens on the endpoint, any request would fail with a connection error
+        let region = s3::Region::Custom {
+            region: "us-east-1".to_string(),
+            endpoint: "http://127.0.0.1:1".to_string(),
+        };
+        let bucket =
+            Bucket::new("test", region, s3::creds::Credentials::anonymous().unwrap()).unwrap();
+        let config = Config {
+            max_upload_size: Some(1024),
+            ..Config::default()
+        };
+        let result = upload_local_file(&path, "large", &bucket, &config);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            format!(
+                "The file {} is 2048 bytes, more than the max_upload_size of 1024 bytes",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn listing_prefixes_skips_the_nested_prefixes() {
+        let roots = roots_of(&[
+            ("/etc/app", "app/"),
+            ("/srv/conf", "app/conf/"),
+            ("/opt", "opt/"),
+        ]);
+        assert_eq!(listing_prefixes(&roots), vec!["app/", "opt/"]);
+        let roots = roots_of(&[("/etc/app", "app/"), ("/home/me", "")]);
+        assert_eq!(listing_prefixes(&roots), vec![""]);
+    }
+
+    #[test]
+    fn trim_newlines_only_removes_the_final_newlines() {
+        assert_eq!(trim_newlines(b"a\nb\n\n"), b"a\nb");
+        assert_eq!(trim_newlines(b"a\nb"), b"a\nb");
+        assert_eq!(trim_newlines(b"\n\n"), b"");
+        assert_eq!(trim_newlines(b"a \n"), b"a ");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn doctor_restricts_the_config_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir =
+            std::env::temp_dir().join(format!("dotfile-test-permissions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config");
+        fs::write(&path, "remote = \"bucket\"\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let unfixed = check_config_permissions(&path, false, true).unwrap();
+        let fixed = check_config_permissions(&path, true, true).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!unfixed);
+        assert!(fixed);
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn doctor_creates_the_missing_root_dir() {
+        let dir = std::env::temp_dir().join(format!("dotfile-test-doctor-{}", std::process::id()));
+        let root = dir.join("dotfiles");
+        // Nothing listens on the endpoint, the connection check fails without a network
+        let config = Config {
+            remote: "bucket".to_string(),
+            remote_endpoint: Some("http://127.0.0.1:1".to_string()),
+            ..Config::default()
+        };
+        let result = doctor(
+            &dir.join("config"),
+            &roots_of(&[(root.to_str().unwrap(), "")]),
+            &config,
+            true,
+            true,
+        );
+        let created = root.is_dir();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(created);
+        // Only the connection problem is left
+        assert_eq!(result.unwrap_err().to_string(), "1 problem(s) found");
+    }
+}
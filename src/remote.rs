@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use s3::{request::ResponseData, Bucket};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+use crate::{error::DotError, rename, retry, snapshot, trash};
+
+/// Keys used by dotfile itself, which are not tracked files
+pub fn is_internal_key(key: &str) -> bool {
+    snapshot::is_snapshot_key(key) || trash::is_trash_key(key) || key == rename::INDEX_KEY
+}
+
+/// Current time in the ISO 8601 basic format, used to name the directories on the remote so
+/// that they sort chronologically
+pub fn timestamp_name() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+/// Fail if `object` is an HTML page rather than the file itself, as returned by a proxy
+/// asking for a login or by a misconfigured endpoint. Tracked files ending in `.html` or
+/// `.htm` are expected to be HTML.
+pub fn check_not_html(key: &str, object: &ResponseData) -> Result<()> {
+    check_content_type(
+        key,
+        object.headers().get("content-type").map(String::as_str),
+    )
+}
+
+/// `check_not_html` for a file whose content type is known from its metadata
+pub fn check_content_type(key: &str, content_type: Option<&str>) -> Result<()> {
+    let is_html = content_type.is_some_and(|content_type| content_type.starts_with("text/html"));
+    if is_html && !key.ends_with(".html") && !key.ends_with(".htm") {
+        bail!(DotError::Remote(format!(
+            "Expected the content of {} but got a text/html page, the endpoint may be misconfigured or require a login",
+            key
+        )));
+    }
+    Ok(())
+}
+
+/// Check if the endpoint answers with an HTML page instead of an S3 response, which is why the
+/// S3 responses would then fail to parse
+pub fn serves_html(bucket: &Bucket) -> bool {
+    bucket
+        .head_object("/")
+        .map(|(head, _)| {
+            head.content_type
+                .is_some_and(|content_type| content_type.starts_with("text/html"))
+        })
+        .unwrap_or(false)
+}
+
+pub fn list_keys(bucket: &Bucket, prefix: &str) -> Result<Vec<String>> {
+    // The listing only fails with an error, retried if it comes from the connection
+    let results = retry::with_retries(
+        "the bucket listing",
+        || bucket.list(prefix.to_string(), None),
+        |_| 200,
+    )
+    .context("Could not list the bucket content. It could be an invalid region or endpoint, invalid credentials, or network issues.")?;
+    Ok(results
+        .into_iter()
+        .flat_map(|result| result.contents)
+        .map(|file| file.key)
+        .collect())
+}
+
+pub fn copy(bucket: &Bucket, from: &str, to: &str) -> Result<()> {
+    let status_code = bucket
+        .copy_object_internal(from, to)
+        .with_context(|| format!("Error copying {} to {}", from, to))?;
+    match status_code {
+        // https://docs.aws.amazon.com/AmazonS3/latest/API/API_CopyObject.html
+        200 => Ok(()),
+        403 => bail!(DotError::Unauthorized("Copy failed with error 403: Forbidden. Please check that your credentials allows you to upload files to the S3 bucket".to_string())),
+        err => bail!(DotError::from_status(err, format!("Copy of {} to {} failed with error code {}", from, to, err))),
+    }
+}
+
+/// Size and dates of a remote file, retrieved without its content
+pub struct Stat {
+    pub size: u64,
+    /// Upload time of the file
+    pub last_modified: OffsetDateTime,
+    pub content_type: Option<String>,
+    /// User metadata stored with the file, without the `x-amz-meta-` prefix
+    pub metadata: HashMap<String, String>,
+}
+
+/// Metadata of the single file `key`, without listing the remote. `None` if it does not exist.
+pub fn stat(bucket: &Bucket, key: &str) -> Result<Option<Stat>> {
+    let (head, status_code) =
+        retry::with_retries(key, || bucket.head_object(key), |(_, status)| *status)
+            .with_context(|| format!("Could not get the metadata of {} from S3", key))?;
+    match status_code {
+        200 => {}
+        404 => return Ok(None),
+        err => bail!(DotError::from_status(
+            err,
+            format!("Could not get the metadata of {}, error code {}", key, err)
+        )),
+    }
+    let last_modified = OffsetDateTime::parse(
+        head.last_modified
+            .as_deref()
+            .context("The remote file has no modification date")?,
+        &Rfc2822,
+    )
+    .context("Error parsing the file modification date from the aws s3 header")?;
+    Ok(Some(Stat {
+        size: head.content_length.unwrap_or_default().try_into()?,
+        last_modified,
+        content_type: head.content_type,
+        metadata: head.metadata.unwrap_or_default(),
+    }))
+}
+
+/// Move `from` to `to` on the remote, as S3 has no rename: `from` is copied, then deleted
+pub fn rename(bucket: &Bucket, from: &str, to: &str) -> Result<()> {
+    copy(bucket, from, to)?;
+    delete(bucket, from)
+}
+
+/// Number of deletions sent at the same time by `delete_many`
+const PARALLEL_DELETIONS: usize = 16;
+
+/// Delete all the `keys`, several at a time. A key that cannot be deleted is reported and the
+/// others are still deleted, the error comes at the end.
+///
+/// Stopgap: this sends one DELETE request per key. The S3 client has no DeleteObjects, which
+/// removes up to 1000 keys in a single request. It needs a signed `POST /?delete` with an XML
+/// body and its Content-MD5, and should replace these threads once the client can send it.
+pub fn delete_many(bucket: &Bucket, keys: &[String]) -> Result<()> {
+    let mut failed = 0;
+    for batch in keys.chunks(PARALLEL_DELETIONS) {
+        let results: Vec<Result<()>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|key| {
+                    let bucket = bucket.clone();
+                    scope.spawn(move || delete(&bucket, key))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("A deletion thread panicked")))
+                })
+                .collect()
+        });
+        for (key, result) in batch.iter().zip(results) {
+            if let Err(err) = result {
+                warn!("Could not delete {}: {:#}", key, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "{} of the {} files could not be deleted",
+            failed,
+            keys.len()
+        );
+    }
+    Ok(())
+}
+
+/// Check if `key` is on the remote, without retrieving its content
+pub fn exists(bucket: &Bucket, key: &str) -> Result<bool> {
+    let (_, status_code) =
+        retry::with_retries(key, || bucket.head_object(key), |(_, status)| *status)
+            .with_context(|| format!("Could not get the metadata of {} from S3", key))?;
+    match status_code {
+        200 => Ok(true),
+        404 => Ok(false),
+        err => bail!(DotError::from_status(
+            err,
+            format!("Could not check if {} exists, error code {}", key, err)
+        )),
+    }
+}
+
+pub fn delete(bucket: &Bucket, key: &str) -> Result<()> {
+    let response = retry::response(key, || bucket.delete_object(key))?;
+    match response.status_code() {
+        204 => Ok(()),
+        403 => bail!(DotError::Unauthorized("Deletion failed with error 403: Forbidden. Please check that your credentials allows you to delete files to the S3 bucket".to_string())),
+        err => bail!(DotError::from_status(err, format!("Deletion of {} failed with error code {}", key, err))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_page_instead_of_a_file() {
+        let err = check_content_type(".bashrc", Some("text/html; charset=utf-8")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DotError>(),
+            Some(DotError::Remote(_))
+        ));
+        assert!(check_content_type("notes/index.html", Some("text/html")).is_ok());
+        assert!(check_content_type("notes/index.htm", Some("text/html")).is_ok());
+    }
+
+    #[test]
+    fn other_content_types() {
+        assert!(check_content_type(".bashrc", Some("application/octet-stream")).is_ok());
+        assert!(check_content_type(".bashrc", Some("text/plain")).is_ok());
+        assert!(check_content_type(".bashrc", None).is_ok());
+    }
+}
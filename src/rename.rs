@@ -0,0 +1,186 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use s3::Bucket;
+
+use crate::{error::DotError, retry};
+
+/// Remote object mapping the keys renamed by a regex rule to the key of their local file, since
+/// these rules cannot be reversed
+pub const INDEX_KEY: &str = ".renamed-keys.json";
+
+/// A rule of the `rename_rules` setting, rewriting the remote keys produced by track
+#[derive(Debug, Clone)]
+pub enum RenameRule {
+    /// `old/=new/`: the keys starting with `old/` start with `new/` instead. Sync maps them
+    /// back to the original local file
+    Prefix { from: String, to: String },
+    /// `from_regex -> to`: the match of the regex is replaced with `to`, where `$1`, `$2`...
+    /// are the capture groups. It cannot be reversed, so track records the keys it changes in
+    /// the `INDEX_KEY` object
+    Regex { from: Regex, to: String },
+}
+
+impl RenameRule {
+    pub fn parse(rule: &str) -> Result<RenameRule> {
+        if let Some((from, to)) = rule.split_once(" -> ") {
+            let from = Regex::new(from.trim())
+                .with_context(|| format!("Invalid regex in the rename rule {}", rule))?;
+            return Ok(RenameRule::Regex {
+                from,
+                to: to.trim().to_string(),
+            });
+        }
+        let (from, to) = rule.split_once('=').with_context(|| {
+            format!(
+                "Invalid rename rule {}, expected old_prefix=new_prefix or from_regex -> to",
+                rule
+            )
+        })?;
+        Ok(RenameRule::Prefix {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    pub fn is_regex(&self) -> bool {
+        matches!(self, RenameRule::Regex { .. })
+    }
+
+    fn apply(&self, key: &str) -> Option<String> {
+        match self {
+            RenameRule::Prefix { from, to } => key
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{}{}", to, rest)),
+            RenameRule::Regex { from, to } => from
+                .is_match(key)
+                .then(|| from.replace(key, to.as_str()).into_owned()),
+        }
+    }
+
+    fn reverse(&self, key: &str) -> Option<String> {
+        match self {
+            RenameRule::Prefix { from, to } => key
+                .strip_prefix(to.as_str())
+                .map(|rest| format!("{}{}", from, rest)),
+            RenameRule::Regex { .. } => None,
+        }
+    }
+}
+
+/// Apply the first rule of `rules` matching `key`
+pub fn rename(rules: &[RenameRule], key: &str) -> String {
+    rules
+        .iter()
+        .find_map(|rule| rule.apply(key))
+        .unwrap_or_else(|| key.to_string())
+}
+
+/// Check if the rule of `rules` renaming `key` is a regex rule
+pub fn renamed_by_regex(rules: &[RenameRule], key: &str) -> bool {
+    rules
+        .iter()
+        .find(|rule| rule.apply(key).is_some())
+        .is_some_and(RenameRule::is_regex)
+}
+
+/// The key `key` was renamed from: the one recorded in `index` for the regex rules, otherwise
+/// the reverse of the first prefix rule whose new prefix matches it
+pub fn original(rules: &[RenameRule], index: &BTreeMap<String, String>, key: &str) -> String {
+    if let Some(original) = index.get(key) {
+        return original.clone();
+    }
+    rules
+        .iter()
+        .find_map(|rule| rule.reverse(key))
+        .unwrap_or_else(|| key.to_string())
+}
+
+/// The keys renamed by a regex rule with the key they were renamed from, empty if nothing was
+/// renamed yet
+pub fn load_index(bucket: &Bucket) -> Result<BTreeMap<String, String>> {
+    let response = retry::response(INDEX_KEY, || bucket.get_object(INDEX_KEY))
+        .context("Could not retrieve the renamed keys from S3")?;
+    match response.status_code() {
+        200 => serde_json::from_slice(response.as_slice())
+            .with_context(|| format!("Invalid list of renamed keys in {}", INDEX_KEY)),
+        404 => Ok(BTreeMap::new()),
+        err => bail!(DotError::from_status(
+            err,
+            format!("Could not retrieve the renamed keys, error code {}", err)
+        )),
+    }
+}
+
+pub fn save_index(bucket: &Bucket, index: &BTreeMap<String, String>) -> Result<()> {
+    let data = serde_json::to_vec_pretty(index)?;
+    let response = retry::response(INDEX_KEY, || bucket.put_object(INDEX_KEY, &data))
+        .context("Could not save the renamed keys to S3")?;
+    match response.status_code() {
+        200 => Ok(()),
+        err => bail!(DotError::from_status(
+            err,
+            format!("Could not save the renamed keys, error code {}", err)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<RenameRule> {
+        rules
+            .iter()
+            .map(|rule| RenameRule::parse(rule).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn prefix_rename() {
+        let rules = rules(&[".vim/=vim/", ".config/=config/"]);
+        assert_eq!(rename(&rules, ".vim/vimrc"), "vim/vimrc");
+        assert_eq!(rename(&rules, ".config/git/config"), "config/git/config");
+        assert_eq!(rename(&rules, ".bashrc"), ".bashrc");
+        let index = BTreeMap::new();
+        assert_eq!(original(&rules, &index, "vim/vimrc"), ".vim/vimrc");
+        assert_eq!(original(&rules, &index, ".bashrc"), ".bashrc");
+        assert!(!renamed_by_regex(&rules, ".vim/vimrc"));
+    }
+
+    #[test]
+    fn regex_capture_groups() {
+        let rules = rules(&[r"^\.config/([^/]+)/(.*)$ -> apps/$1/$2"]);
+        assert_eq!(rename(&rules, ".config/git/config"), "apps/git/config");
+        assert_eq!(rename(&rules, ".bashrc"), ".bashrc");
+        assert!(renamed_by_regex(&rules, ".config/git/config"));
+        assert!(!renamed_by_regex(&rules, ".bashrc"));
+        // Only the index can map the key back
+        let mut index = BTreeMap::new();
+        assert_eq!(
+            original(&rules, &index, "apps/git/config"),
+            "apps/git/config"
+        );
+        index.insert(
+            "apps/git/config".to_string(),
+            ".config/git/config".to_string(),
+        );
+        assert_eq!(
+            original(&rules, &index, "apps/git/config"),
+            ".config/git/config"
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = rules(&["a/=b/", "a/=c/"]);
+        assert_eq!(rename(&rules, "a/x"), "b/x");
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(RenameRule::parse("no separator").is_err());
+        assert!(RenameRule::parse("( -> x").is_err());
+    }
+}
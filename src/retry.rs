@@ -0,0 +1,104 @@
+use std::{
+    cell::Cell,
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use s3::{error::S3Error, request::ResponseData};
+
+use crate::config::Config;
+
+/// Number of retries and delay before the first one, set once from the configuration
+static SETTINGS: OnceLock<(u32, Duration)> = OnceLock::new();
+
+thread_local! {
+    /// Time after which the requests of the current thread are not retried any more
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Use the `max_retries` and `retry_delay` of `config` for all the requests from now on
+pub fn configure(config: &Config) {
+    let _ = SETTINGS.set((
+        config.max_retries.unwrap_or(3),
+        Duration::from_millis(config.retry_delay.unwrap_or(500)),
+    ));
+}
+
+/// Run `request`, and run it again after a failure that may be temporary: a connection error,
+/// or an answer of the server whose `status` is a 5xx. The 4xx are returned right away. The
+/// delay between two attempts doubles each time. No attempt starts after the deadline set by
+/// `until`.
+pub fn with_retries<T>(
+    what: &str,
+    mut request: impl FnMut() -> Result<T, S3Error>,
+    status: impl Fn(&T) -> u16,
+) -> Result<T, S3Error> {
+    let (max_retries, mut delay) = *SETTINGS.get_or_init(|| (3, Duration::from_millis(500)));
+    let mut retries = 0;
+    loop {
+        let result = request();
+        let temporary = match &result {
+            Ok(response) => status(response) >= 500,
+            Err(S3Error::Atto(_)) => true,
+            Err(_) => false,
+        };
+        let past_deadline = DEADLINE
+            .with(Cell::get)
+            .is_some_and(|deadline| Instant::now() + delay >= deadline);
+        if !temporary || retries >= max_retries || past_deadline {
+            return result;
+        }
+        retries += 1;
+        debug!(
+            "The request for {} failed, retrying in {}ms ({}/{})",
+            what,
+            delay.as_millis(),
+            retries,
+            max_retries
+        );
+        thread::sleep(delay);
+        delay *= 2;
+    }
+}
+
+/// Run `transfer`, whose requests are not retried after `deadline`. A request abandoned after
+/// the timeout of a transfer reaches the deadline, so it is never attempted again.
+pub fn until<T>(deadline: Option<Instant>, transfer: impl FnOnce() -> T) -> T {
+    let previous = DEADLINE.with(|current| current.replace(deadline));
+    let result = transfer();
+    DEADLINE.with(|current| current.set(previous));
+    result
+}
+
+/// `with_retries` for the requests answering with a `ResponseData`
+pub fn response(
+    what: &str,
+    request: impl FnMut() -> Result<ResponseData, S3Error>,
+) -> Result<ResponseData, S3Error> {
+    with_retries(what, request, ResponseData::status_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_retry_after_the_deadline() {
+        let mut attempts = 0;
+        let status = until(Some(Instant::now()), || {
+            with_retries(
+                "key",
+                || {
+                    attempts += 1;
+                    Ok(503)
+                },
+                |status| *status,
+            )
+        });
+        assert_eq!(status.unwrap(), 503);
+        assert_eq!(attempts, 1);
+        assert_eq!(DEADLINE.with(Cell::get), None);
+    }
+}
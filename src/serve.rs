@@ -0,0 +1,274 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use s3::Bucket;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{Config, Root},
+    error::DotError,
+    is_safe_key, list_each, listing_prefixes, local_path,
+    remote::{check_not_html, is_internal_key, list_keys},
+    retry, sync, synced, SyncArgs, SyncSummary,
+};
+
+/// A command read from stdin, one JSON object per line, for example
+/// `{"command": "get", "key": ".bashrc"}`
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    /// Keys of the tracked files. The listing is kept between commands unless `refresh` is set
+    List {
+        #[serde(default)]
+        refresh: bool,
+    },
+    /// Content of a tracked file, which must be a text file
+    Get { key: String },
+    /// State of each tracked file compared with the last sync, without retrieving them
+    Status,
+    /// Run a sync with the options of the command line in `args`, for example `["--dry-run"]`.
+    /// Nobody can be prompted, the conflicts are skipped unless `--strategy` is given
+    Sync {
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// The options of a sync requested with the `sync` command
+#[derive(Parser)]
+#[command(name = "sync")]
+struct SyncCommand {
+    #[command(flatten)]
+    args: SyncArgs,
+}
+
+/// State of a tracked file compared with the last sync
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum FileStatus {
+    Synced,
+    /// There is no local file
+    Missing,
+    LocalChanged,
+    RemoteChanged,
+    /// Changed on both sides, or never synced
+    Changed,
+}
+
+/// The answer to a command, written as one JSON object per line on stdout
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Response {
+    Keys { keys: Vec<String> },
+    Content { key: String, content: String },
+    Status { files: BTreeMap<String, FileStatus> },
+    Summary { summary: SyncSummary },
+    Error { error: String },
+}
+
+/// Answer the commands read from stdin until it is closed, reusing the same connection to
+/// the remote and the same listing for all of them
+pub fn serve(bucket: &Bucket, roots: &[Root], config: &Config) -> Result<()> {
+    // The logs are written to stdout, where they would be mixed with the responses
+    log::set_max_level(log::LevelFilter::Off);
+    let mut session = Session {
+        bucket,
+        roots,
+        config,
+        listing: None,
+    };
+    session.answer(std::io::stdin().lock(), std::io::stdout().lock())
+}
+
+/// What is kept from one command to the next
+struct Session<'a> {
+    bucket: &'a Bucket,
+    roots: &'a [Root],
+    config: &'a Config,
+    listing: Option<Vec<String>>,
+}
+
+impl Session<'_> {
+    /// Answer each line of `input` with a line of `output`
+    fn answer(&mut self, input: impl BufRead, mut output: impl Write) -> Result<()> {
+        for line in input.lines() {
+            let line = line.context("Error reading a command from stdin")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Err(err) => Response::Error {
+                    error: format!("Invalid command: {}", err),
+                },
+                Ok(request) => self.handle(request).unwrap_or_else(|err| Response::Error {
+                    error: format!("{:#}", err),
+                }),
+            };
+            serde_json::to_writer(&mut output, &response)?;
+            writeln!(output)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Response> {
+        match request {
+            Request::List { refresh } => {
+                if refresh || self.listing.is_none() {
+                    self.listing = Some(
+                        list_keys(self.bucket, "")?
+                            .into_iter()
+                            .filter(|key| !is_internal_key(key))
+                            .collect(),
+                    );
+                }
+                Ok(Response::Keys {
+                    keys: self.listing.clone().unwrap_or_default(),
+                })
+            }
+            Request::Get { key } => {
+                let object = retry::response(&key, || self.bucket.get_object(&key))
+                    .with_context(|| format!("Could not retrieve file {} from S3", key))?;
+                if object.status_code() != 200 {
+                    bail!(DotError::from_status(
+                        object.status_code(),
+                        format!(
+                            "Could not retrieve file {}, error code {}",
+                            key,
+                            object.status_code()
+                        )
+                    ));
+                }
+                check_not_html(&key, &object)?;
+                let content = String::from_utf8(object.bytes().to_vec())
+                    .context("The remote file is not a text file")?;
+                Ok(Response::Content { key, content })
+            }
+            Request::Status => Ok(Response::Status {
+                files: self.status()?,
+            }),
+            Request::Sync { args } => {
+                let command =
+                    SyncCommand::try_parse_from(std::iter::once("sync".to_string()).chain(args))?;
+                if command.args.interactive_select {
+                    bail!("--interactive-select needs a terminal, it cannot be used with serve");
+                }
+                // The listing may have changed
+                self.listing = None;
+                Ok(Response::Summary {
+                    summary: sync(self.roots, self.config, &command.args, true)?,
+                })
+            }
+        }
+    }
+
+    /// Compare the ETag and the local file of each tracked file with the ones of the last sync
+    fn status(&self) -> Result<BTreeMap<String, FileStatus>> {
+        let previously_synced = match &self.config.synced_keys_file {
+            Some(path) => synced::load(path)?,
+            None => BTreeMap::new(),
+        };
+        let mut files = BTreeMap::new();
+        for prefix in listing_prefixes(self.roots) {
+            list_each(self.bucket, prefix, &mut |file| {
+                if is_internal_key(&file.key) || !is_safe_key(&file.key) {
+                    return Ok(());
+                }
+                let Some(local) = local_path(self.roots, &self.config.original_key(&file.key))
+                else {
+                    return Ok(());
+                };
+                let etag = file.e_tag.as_deref();
+                let synced = previously_synced.get(&file.key);
+                let status = if !local.is_file() {
+                    FileStatus::Missing
+                } else if synced.is_some_and(|synced| synced.unchanged(etag, &local)) {
+                    FileStatus::Synced
+                } else if synced
+                    .is_some_and(|synced| synced.local.is_some() && synced.remote_unchanged(etag))
+                {
+                    FileStatus::LocalChanged
+                } else if synced.is_some_and(|synced| synced.local_unchanged(&local)) {
+                    FileStatus::RemoteChanged
+                } else {
+                    FileStatus::Changed
+                };
+                files.insert(file.key, status);
+                Ok(())
+            })?;
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use s3::{creds::Credentials, Region};
+
+    use super::*;
+
+    /// A session whose listing is already known, with a remote that cannot be reached
+    fn session<'a>(bucket: &'a Bucket, config: &'a Config) -> Session<'a> {
+        Session {
+            bucket,
+            roots: &[],
+            config,
+            listing: Some(vec![".bashrc".to_string(), ".vimrc".to_string()]),
+        }
+    }
+
+    fn offline_bucket() -> Bucket {
+        let region = Region::Custom {
+            region: "us-east-1".to_string(),
+            endpoint: "http://127.0.0.1:1".to_string(),
+        };
+        Bucket::new("test", region, Credentials::anonymous().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn answers_each_line() {
+        let bucket = offline_bucket();
+        let config = Config::default();
+        let mut output = vec![];
+        let input = "{\"command\": \"list\"}\n\nnot json\n{\"command\": \"unknown\"}\n";
+        session(&bucket, &config)
+            .answer(input.as_bytes(), &mut output)
+            .unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], serde_json::json!({"keys": [".bashrc", ".vimrc"]}));
+        assert!(lines[1]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid command"));
+        assert!(lines[2]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Invalid command"));
+    }
+
+    #[test]
+    fn rejects_invalid_sync_options() {
+        let bucket = offline_bucket();
+        let config = Config::default();
+        let mut session = session(&bucket, &config);
+        let request = Request::Sync {
+            args: vec!["--no-such-option".to_string()],
+        };
+        assert!(session.handle(request).is_err());
+        let request = Request::Sync {
+            args: vec!["--interactive-select".to_string()],
+        };
+        assert!(session.handle(request).is_err());
+        // Nothing reached the remote, the listing is kept
+        assert!(session.listing.is_some());
+    }
+}
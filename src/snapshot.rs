@@ -0,0 +1,77 @@
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use s3::Bucket;
+
+use crate::error::DotError;
+use crate::remote::{copy, delete_many, is_internal_key, list_keys, timestamp_name};
+
+/// All the snapshots are stored under this prefix, one sub-directory per snapshot. Hidden like
+/// `.trash/`, and track refuses the keys under it
+pub const SNAPSHOT_PREFIX: &str = ".snapshots/";
+
+pub fn is_snapshot_key(key: &str) -> bool {
+    key.starts_with(SNAPSHOT_PREFIX)
+}
+
+/// Copy every tracked file under a new `.snapshots/<timestamp>/` prefix, then remove the oldest
+/// snapshots if there are more than `keep_snapshots`.
+pub fn create(bucket: &Bucket, keep_snapshots: Option<usize>) -> Result<()> {
+    let name = timestamp_name();
+
+    let keys: Vec<String> = list_keys(bucket, "")?
+        .into_iter()
+        .filter(|key| !is_internal_key(key))
+        .collect();
+    for key in &keys {
+        copy(bucket, key, &format!("{}{}/{}", SNAPSHOT_PREFIX, name, key))?;
+    }
+    info!("Snapshot {} created with {} files", name, keys.len());
+
+    if let Some(keep_snapshots) = keep_snapshots {
+        let snapshots = list(bucket)?;
+        let expired = snapshots.len().saturating_sub(keep_snapshots);
+        for old in &snapshots[..expired] {
+            delete_many(
+                bucket,
+                &list_keys(bucket, &format!("{}{}/", SNAPSHOT_PREFIX, old))?,
+            )?;
+            info!("Removed old snapshot {}", old);
+        }
+    }
+    Ok(())
+}
+
+/// Names of the snapshots available on the remote, oldest first
+pub fn list(bucket: &Bucket) -> Result<Vec<String>> {
+    let names: BTreeSet<String> = list_keys(bucket, SNAPSHOT_PREFIX)?
+        .iter()
+        .filter_map(|key| {
+            key.strip_prefix(SNAPSHOT_PREFIX)
+                .and_then(|rest| rest.split_once('/'))
+                .map(|(name, _)| name.to_string())
+        })
+        .collect();
+    Ok(names.into_iter().collect())
+}
+
+/// Copy the files of a snapshot back to their original keys. Files tracked after the snapshot
+/// was taken are left untouched.
+pub fn restore(bucket: &Bucket, name: &str) -> Result<()> {
+    let prefix = format!("{}{}/", SNAPSHOT_PREFIX, name);
+    let keys = list_keys(bucket, &prefix)?;
+    if keys.is_empty() {
+        bail!(DotError::NotFound(format!(
+            "The snapshot {} does not exist",
+            name
+        )));
+    }
+    for key in &keys {
+        let target = key.strip_prefix(&prefix).context("Invalid snapshot key")?;
+        info!("Restoring {}", target);
+        copy(bucket, key, target)?;
+    }
+    info!("Snapshot {} restored ({} files)", name, keys.len());
+    Ok(())
+}
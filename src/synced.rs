@@ -0,0 +1,101 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+/// State of a remote key at the end of the previous sync
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct SyncedFile {
+    /// ETag of the remote object when it was listed. Only compared for equality, as a token
+    /// that changes with the content: it is not the MD5 of the content for the objects uploaded
+    /// in several parts or encrypted with KMS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// Size and modification time, in nanoseconds, of the local file once it was identical to
+    /// the remote one. Missing if the sync left them different.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local: Option<(u64, i128)>,
+}
+
+impl SyncedFile {
+    /// State of a file that is identical locally and on the remote
+    pub fn in_sync(etag: Option<String>, path: &Path) -> SyncedFile {
+        SyncedFile {
+            etag,
+            local: local_state(path),
+        }
+    }
+
+    /// Check that neither the remote object nor the local file changed since the previous sync
+    pub fn unchanged(&self, etag: Option<&str>, path: &Path) -> bool {
+        self.remote_unchanged(etag) && self.local_unchanged(path)
+    }
+
+    /// Check that the remote object still has the ETag it had at the previous sync
+    pub fn remote_unchanged(&self, etag: Option<&str>) -> bool {
+        etag.is_some() && self.etag.as_deref() == etag
+    }
+
+    /// Check that the local file was identical to the remote one at the previous sync and was
+    /// not modified since
+    pub fn local_unchanged(&self, path: &Path) -> bool {
+        self.local.is_some() && self.local == local_state(path)
+    }
+}
+
+fn local_state(path: &Path) -> Option<(u64, i128)> {
+    let metadata = fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())?;
+    let modified = OffsetDateTime::from(metadata.modified().ok()?);
+    Some((metadata.len(), modified.unix_timestamp_nanos()))
+}
+
+/// The keys present on the remote at the end of the previous sync, with their state. Empty if
+/// sync never ran.
+pub fn load(path: &Path) -> Result<BTreeMap<String, SyncedFile>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Error reading {}", path.display()))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Invalid sync state in {}", path.display()))
+}
+
+pub fn save(path: &Path, keys: &BTreeMap<String, SyncedFile>) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(keys)?)
+        .with_context(|| format!("Error writing {}", path.display()))
+}
+
+/// Directory next to the sync state `path` holding a copy of each synced file as it was at the
+/// end of the previous sync, the common ancestor used to merge the changes made on both sides
+pub fn base_dir(path: &Path) -> PathBuf {
+    path.with_extension("base")
+}
+
+/// Content of `key` at the end of the previous sync, if a copy was kept
+pub fn load_base(dir: &Path, key: &str) -> Option<Vec<u8>> {
+    fs::read(dir.join(key)).ok()
+}
+
+/// Keep a copy of the local file `path` as the base version of `key`, unless the copy is
+/// already there and the file did not change since the previous sync
+pub fn save_base(dir: &Path, key: &str, path: &Path, previous: Option<&SyncedFile>) -> Result<()> {
+    let base = dir.join(key);
+    if !path.is_file() || (base.is_file() && previous.is_some_and(|p| p.local_unchanged(path))) {
+        return Ok(());
+    }
+    if let Some(parent) = base.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create the directory {}", parent.display()))?;
+    }
+    fs::copy(path, &base)
+        .with_context(|| format!("Could not keep a copy of {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use log::info;
+use s3::Bucket;
+
+use crate::remote::{delete_many, list_keys, rename, timestamp_name};
+
+/// Forgotten files are moved under this prefix, in one sub-directory per deletion time
+pub const TRASH_PREFIX: &str = ".trash/";
+
+pub fn is_trash_key(key: &str) -> bool {
+    key.starts_with(TRASH_PREFIX)
+}
+
+/// Move `key` under a new `.trash/<timestamp>/` prefix instead of deleting it
+pub fn move_to_trash(bucket: &Bucket, key: &str) -> Result<()> {
+    rename(
+        bucket,
+        key,
+        &format!("{}{}/{}", TRASH_PREFIX, timestamp_name(), key),
+    )
+}
+
+/// The files in the trash, as `(deletion time, key)`, oldest first
+pub fn list(bucket: &Bucket) -> Result<Vec<(String, String)>> {
+    let mut files: Vec<(String, String)> = list_keys(bucket, TRASH_PREFIX)?
+        .iter()
+        .filter_map(|trashed| {
+            trashed
+                .strip_prefix(TRASH_PREFIX)
+                .and_then(|rest| rest.split_once('/'))
+                .map(|(time, key)| (time.to_string(), key.to_string()))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Move the most recently trashed version of `key` back to its original location
+pub fn restore(bucket: &Bucket, key: &str) -> Result<()> {
+    let (time, _) = list(bucket)?
+        .into_iter()
+        .rev()
+        .find(|(_, trashed)| trashed == key)
+        .with_context(|| format!("The file {} is not in the trash", key))?;
+    let trashed = format!("{}{}/{}", TRASH_PREFIX, time, key);
+    rename(bucket, &trashed, key)?;
+    info!("The file {} has been restored", key);
+    Ok(())
+}
+
+/// Permanently delete every file in the trash
+pub fn empty(bucket: &Bucket) -> Result<()> {
+    let keys = list_keys(bucket, TRASH_PREFIX)?;
+    delete_many(bucket, &keys)?;
+    info!("Removed {} files from the trash", keys.len());
+    Ok(())
+}
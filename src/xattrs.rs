@@ -0,0 +1,115 @@
+use std::{ffi::OsString, path::Path};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+/// Object metadata storing the extended attributes of the file when it was uploaded, as a
+/// comma-separated list of `name:value`, both hex-encoded since S3 metadata must be ASCII
+pub const XATTRS_METADATA: &str = "x-amz-meta-xattrs";
+
+/// Extended attributes of `path` in the format of `XATTRS_METADATA`, or `None` if it has
+/// none or the platform does not support them
+pub fn encode(path: &Path) -> Result<Option<String>> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(None);
+    }
+    let mut attributes = vec![];
+    for name in xattr::list(path).with_context(|| {
+        format!(
+            "Could not list the extended attributes of {}",
+            path.display()
+        )
+    })? {
+        // Attribute names are UTF-8 in practice, the others are not worth the trouble
+        let Some(utf8_name) = name.to_str() else {
+            warn!(
+                "Skipping the extended attribute {:?} of {}, its name is not valid UTF-8",
+                name,
+                path.display()
+            );
+            continue;
+        };
+        if let Some(value) = xattr::get(path, &name).with_context(|| {
+            format!(
+                "Could not read the extended attribute {:?} of {}",
+                name,
+                path.display()
+            )
+        })? {
+            attributes.push(format!(
+                "{}:{}",
+                to_hex(utf8_name.as_bytes()),
+                to_hex(&value)
+            ));
+        }
+    }
+    Ok((!attributes.is_empty()).then(|| attributes.join(",")))
+}
+
+/// Parse the extended attributes stored in `XATTRS_METADATA`, ignoring malformed entries
+pub fn decode(encoded: &str) -> Vec<(OsString, Vec<u8>)> {
+    encoded
+        .split(',')
+        .filter_map(|attribute| {
+            let (name, value) = attribute.split_once(':')?;
+            let name = String::from_utf8(from_hex(name)?).ok()?;
+            Some((OsString::from(name), from_hex(value)?))
+        })
+        .collect()
+}
+
+/// Set the extended attributes on `path`. Attributes that cannot be set, for example the
+/// ones reserved to the administrator, are reported and skipped.
+pub fn apply(path: &Path, attributes: &[(OsString, Vec<u8>)]) {
+    if !xattr::SUPPORTED_PLATFORM {
+        return;
+    }
+    for (name, value) in attributes {
+        if let Err(err) = xattr::set(path, name, value) {
+            warn!(
+                "Could not set the extended attribute {:?} on {}: {}",
+                name,
+                path.display(),
+                err
+            );
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_hex_pairs() {
+        assert_eq!(
+            decode("757365722e74657374:616263,757365722e65:"),
+            vec![
+                (OsString::from("user.test"), b"abc".to_vec()),
+                (OsString::from("user.e"), vec![]),
+            ]
+        );
+        assert_eq!(to_hex(b"user.test"), "757365722e74657374");
+    }
+
+    #[test]
+    fn skips_the_malformed_entries() {
+        assert!(decode("").is_empty());
+        assert!(decode("no-separator").is_empty());
+        assert!(decode("zz:00,616:00,6162:0").is_empty());
+    }
+}